@@ -74,6 +74,9 @@ async fn main() {
 }
 
 async fn main_async() {
+    #[cfg(target_arch = "wasm32")]
+    kube_runtime_abi::init_logging();
+    #[cfg(not(target_arch = "wasm32"))]
     tracing_subscriber::fmt::init();
     println!("main launched simple child");
 
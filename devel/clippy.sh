@@ -5,8 +5,6 @@ set -o pipefail
 
 ROOT_DIR=$(realpath $(dirname $(dirname "${BASH_SOURCE}")))
 
-# Required for /pkg/controller to compile
-export COMPILE_WITH_UNINSTANTIATE="TRUE"
 # Required for /controllers/comb-rust-controller and /controllers/ring-rust-controller to compile
 export COMPILE_NONCE="REPLACEMEREPLACEME"
 
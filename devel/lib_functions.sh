@@ -96,6 +96,10 @@ spec:
       value: "info"
     - name: NR_OPERATORS
       value: "${NR_CONTROLLERS}"
+    - name: CONTROLLER_ALLOCATION
+      value: "${CONTROLLER_ALLOCATION:-pooling}"
+    - name: CONTROLLER_SWAPPING
+      value: "${CONTROLLER_SWAPPING:-true}"
 ---
 EOF
 }
@@ -119,6 +123,10 @@ spec:
       value: "info"
     - name: PREDICTION_SERVER
       value: "${SERVER}"
+    - name: CONTROLLER_ALLOCATION
+      value: "${CONTROLLER_ALLOCATION:-pooling}"
+    - name: CONTROLLER_SWAPPING
+      value: "${CONTROLLER_SWAPPING:-true}"
     resources:
       requests:
         memory: "640Mi"
@@ -18,8 +18,6 @@ cd "${SCRIPT_ROOT}/.."
 NR_CONTROLLERS=$1
 
 #export RUST_BACKTRACE=1
-export COMPILE_WITH_UNINSTANTIATE="TRUE"
-#export COMPILE_WITH_UNINSTANTIATE="FALSE"
 #export RUSTFLAGS="-g"
 #export OPENSSL_DIR="/usr"
 #echo $OPENSSL_DIR
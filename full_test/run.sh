@@ -60,7 +60,8 @@ for ((run = 0; run <= 5; run += 1)); do
             sudo pkill -P $profilePID
         fi
 
-        export COMPILE_WITH_UNINSTANTIATE="TRUE"
+        export CONTROLLER_ALLOCATION="pooling"
+        export CONTROLLER_SWAPPING="true"
 
         if [ ! -f ./test_results_run$run/out_wasm_${nrworkers}_uninst.csv ]; then
             ./devel/create_cluster.sh
@@ -73,7 +74,8 @@ for ((run = 0; run <= 5; run += 1)); do
             sudo pkill -P $profilePID
         fi
 
-        export COMPILE_WITH_UNINSTANTIATE="FALSE"
+        export CONTROLLER_ALLOCATION="on-demand"
+        export CONTROLLER_SWAPPING="false"
 
         if [ ! -f ./test_results_run$run/out_wasm_${nrworkers}.csv ]; then
             ./devel/create_cluster.sh
@@ -115,7 +117,8 @@ for ((run = 0; run <= 5; run += 1)); do
             sudo pkill -P $profilePID
         fi
 
-        export COMPILE_WITH_UNINSTANTIATE="TRUE"
+        export CONTROLLER_ALLOCATION="pooling"
+        export CONTROLLER_SWAPPING="true"
 
         if [ ! -f ./test_heap_results_run$run/${heap_mem_size}_out_wasm_${nrworkers}_uninst.csv ]; then
             ./devel/create_cluster.sh
@@ -127,7 +130,8 @@ for ((run = 0; run <= 5; run += 1)); do
             sudo pkill -P $profilePID
         fi
 
-        export COMPILE_WITH_UNINSTANTIATE="FALSE"
+        export CONTROLLER_ALLOCATION="on-demand"
+        export CONTROLLER_SWAPPING="false"
 
         if [ ! -f ./test_heap_results_run$run/${heap_mem_size}_out_wasm_${nrworkers}.csv ]; then
             ./devel/create_cluster.sh
@@ -16,7 +16,8 @@ sleepperiod=60
 run=1
 nritters=100
 
-export COMPILE_WITH_UNINSTANTIATE="TRUE"
+export CONTROLLER_ALLOCATION="pooling"
+export CONTROLLER_SWAPPING="true"
 export HEAP_MEM_SIZE=90000000
 export RUST_BACKTRACE=1
 ./devel/create_cluster.sh
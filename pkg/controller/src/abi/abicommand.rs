@@ -1,4 +1,6 @@
+use crate::runtime::http_engine::watch_filter::WatchFilter;
 use core::fmt::Debug;
+use serde::Serialize;
 use std::time::Duration;
 
 #[derive(PartialEq, Eq, Hash, Debug)]
@@ -6,12 +8,57 @@ pub struct AsyncResult {
     pub async_request_id: u64,
     pub value: Option<bytes::Bytes>,
     pub finished: bool,
+    pub error: Option<HostError>,
+}
+
+impl AsyncResult {
+    /// Final result of a request that failed on the host
+    pub fn failed(async_request_id: u64, error: HostError) -> Self {
+        Self {
+            async_request_id,
+            value: None,
+            finished: true,
+            error: Some(error),
+        }
+    }
+}
+
+// the variants are serialized by index, keep them in the same order as in kube-runtime-abi
+#[derive(Serialize, PartialEq, Eq, Hash, Debug, Clone, Copy)]
+pub enum HostErrorKind {
+    InvalidRequest,
+    Transport,
+    Internal,
+    Denied,
+}
+
+/// Error payload passed to the guest through `wakeup`
+#[derive(Serialize, PartialEq, Eq, Hash, Debug, Clone)]
+pub struct HostError {
+    pub kind: HostErrorKind,
+    pub message: String,
+}
+
+impl HostError {
+    pub fn new(kind: HostErrorKind, message: impl ToString) -> Self {
+        Self {
+            kind,
+            message: message.to_string(),
+        }
+    }
+}
+
+impl std::fmt::Display for HostError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.kind, self.message)
+    }
 }
 
 #[derive(Debug)]
 pub enum AsyncRequestValue {
     Http(http::Request<Vec<u8>>),
-    HttpStream(http::Request<Vec<u8>>),
+    HttpStream(http::Request<Vec<u8>>, Option<WatchFilter>),
+    Outbound(http::Request<Vec<u8>>),
     Delay(Duration),
 }
 
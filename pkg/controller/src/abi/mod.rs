@@ -1,18 +1,48 @@
 use crate::runtime::controller_ctx::ControllerCtx;
 use std::sync::atomic::Ordering;
 use std::time::Duration;
-use wasmtime::{AsContextMut, Caller, Instance, Linker};
+use wasmtime::{AsContextMut, Caller, Instance, Linker, Trap};
 
 pub mod abicommand;
 pub mod opcall;
+pub mod receive_buffer;
+pub mod version;
 
+use crate::logging::GuestLogRecord;
+use crate::runtime::http_engine::watch_filter::WatchFilter;
 use crate::runtime::http_engine::HttpRequest;
 pub use abicommand::AsyncRequestValue;
+use abicommand::AsyncResult;
+pub use abicommand::{HostError, HostErrorKind};
+use receive_buffer::{encode_frame, ReceiveBuffer};
+
+// flags passed to the `wakeup` export
+const WAKEUP_FINISHED: u32 = 1;
+const WAKEUP_ERROR: u32 = 2;
+// only used in frames, `wakeup` passes a null pointer instead
+const WAKEUP_VALUE: u32 = 4;
 
 pub fn register_imports(linker: &mut Linker<ControllerCtx>) -> anyhow::Result<()> {
     linker.func_wrap("http-proxy-abi", "request", abi_request)?;
-    linker.func_wrap("http-proxy-abi", "request_stream", abi_request)?;
+    linker.func_wrap("http-proxy-abi", "request_stream", abi_request_stream)?;
+    linker.func_wrap(
+        "http-proxy-abi",
+        "request_stream_filtered",
+        abi_request_stream_filtered,
+    )?;
+    linker.func_wrap(
+        "http-outbound-abi",
+        "outbound_request",
+        abi_outbound_request,
+    )?;
     linker.func_wrap("delay-abi", "delay", abi_delay)?;
+    linker.func_wrap("async-abi", "cancel", abi_cancel)?;
+    linker.func_wrap(
+        "async-abi",
+        "register_receive_buffer",
+        abi_register_receive_buffer,
+    )?;
+    linker.func_wrap("log-abi", "event", abi_log)?;
 
     Ok(())
 }
@@ -45,17 +75,68 @@ where
 
 // TODO maybe make abi for memory loading??
 
+/// Deliver results to the guest, in a single call if the guest supports batched wakeups
 pub(crate) async fn wakeup<S>(
     mut store: S,
     instance: &Instance,
-    async_request_id: u64,
-    value: Option<bytes::Bytes>,
-    finished: bool,
+    results: Vec<AsyncResult>,
 ) -> anyhow::Result<()>
 where
-    S: AsContextMut,
-    S::Data: Send,
+    S: AsContextMut<Data = ControllerCtx>,
+{
+    if !store
+        .as_context_mut()
+        .data()
+        .abi_version
+        .supports_batch_wakeup()
+    {
+        for result in results {
+            wakeup_one(&mut store, instance, result).await?;
+        }
+
+        return Ok(());
+    }
+
+    let mut frames = Vec::new();
+    for result in results {
+        let (async_request_id, flags, value) = wakeup_args(result)?;
+        encode_result_frame(&mut frames, async_request_id, flags, value.as_deref());
+    }
+
+    if write_receive_buffer(&mut store, instance, &frames)? {
+        return Ok(());
+    }
+
+    // the batch doesn't fit in the receive buffer, hand it over in newly allocated memory
+    let frames_ptr = allocate(&mut store, instance, frames.len() as u32).await?;
+    let memory = instance
+        .get_memory(&mut store, "memory")
+        .expect("memory not found");
+    memory.write(&mut store, frames_ptr as usize, &frames)?;
+
+    instance
+        .get_typed_func::<(u32, u32), (), _>(&mut store, "wakeup_batch")?
+        .call(&mut store, (frames_ptr, frames.len() as u32))?;
+
+    Ok(())
+}
+
+async fn wakeup_one<S>(mut store: S, instance: &Instance, result: AsyncResult) -> anyhow::Result<()>
+where
+    S: AsContextMut<Data = ControllerCtx>,
 {
+    let (async_request_id, flags, value) = wakeup_args(result)?;
+
+    // use the receive buffer of the guest if the result fits, this avoids an allocation per result
+    if store.as_context_mut().data().receive_buffer.is_some() {
+        let mut frames = Vec::new();
+        encode_result_frame(&mut frames, async_request_id, flags, value.as_deref());
+
+        if write_receive_buffer(&mut store, instance, &frames)? {
+            return Ok(());
+        }
+    }
+
     // allocates the memory of a request
     let (memory_location_ptr, memory_location_size) = match value {
         None => (std::ptr::null::<*const u32>() as u32, 0),
@@ -79,7 +160,7 @@ where
         &mut store,
         (
             async_request_id,
-            if finished { 1 } else { 0 },
+            flags,
             memory_location_ptr,
             memory_location_size as u32,
         ),
@@ -88,36 +169,184 @@ where
     Ok(())
 }
 
-fn abi_request(mut caller: Caller<'_, ControllerCtx>, ptr: u32, size: u32, stream: u32) -> u64 {
-    let inner_request: HttpRequest<Vec<u8>> = {
-        let memory = caller
-            .get_export("memory")
-            .expect("no memory found")
-            .into_memory()
-            .expect("no memory found");
+/// The async request id, flags and payload passed to the guest for a result
+fn wakeup_args(result: AsyncResult) -> anyhow::Result<(u64, u32, Option<bytes::Bytes>)> {
+    let (flags, value) = match result.error {
+        None if result.finished => (WAKEUP_FINISHED, result.value),
+        None => (0, result.value),
+        Some(error) => (
+            WAKEUP_FINISHED | WAKEUP_ERROR,
+            Some(bytes::Bytes::from(bincode::serialize(&error)?)),
+        ),
+    };
+
+    Ok((result.async_request_id, flags, value))
+}
 
-        let inner_req_bytes =
-            &memory.data_mut(caller.as_context_mut())[(ptr as usize)..((ptr + size) as usize)];
+fn encode_result_frame(
+    frames: &mut Vec<u8>,
+    async_request_id: u64,
+    flags: u32,
+    value: Option<&[u8]>,
+) {
+    match value {
+        Some(value) => encode_frame(frames, async_request_id, flags | WAKEUP_VALUE, value),
+        None => encode_frame(frames, async_request_id, flags, &[]),
+    }
+}
 
-        bincode::deserialize(inner_req_bytes).expect("deserialize failed")
+/// Write the frames in the receive buffer and call `wakeup_buffered`, returns false if they don't fit
+fn write_receive_buffer<S>(mut store: S, instance: &Instance, frames: &[u8]) -> anyhow::Result<bool>
+where
+    S: AsContextMut<Data = ControllerCtx>,
+{
+    let receive_buffer = match store.as_context_mut().data().receive_buffer {
+        Some(receive_buffer) if frames.len() <= receive_buffer.len as usize => receive_buffer,
+        _ => return Ok(false),
     };
 
+    let memory = instance
+        .get_memory(&mut store, "memory")
+        .expect("memory not found");
+    memory.write(&mut store, receive_buffer.ptr as usize, frames)?;
+
+    instance
+        .get_typed_func::<u32, (), _>(&mut store, "wakeup_buffered")?
+        .call(&mut store, frames.len() as u32)?;
+
+    Ok(true)
+}
+
+/// Copy `size` bytes at `ptr` out of the memory of the guest
+fn read_guest_bytes(
+    caller: &mut Caller<'_, ControllerCtx>,
+    ptr: u32,
+    size: u32,
+) -> Result<Option<Vec<u8>>, Trap> {
+    let memory = caller
+        .get_export("memory")
+        .and_then(|export| export.into_memory())
+        .ok_or_else(|| Trap::new("no memory exported by the module"))?;
+
+    let start = ptr as usize;
+    let end = start + size as usize;
+
+    Ok(memory
+        .data(&caller)
+        .get(start..end)
+        .map(|bytes| bytes.to_vec()))
+}
+
+/// Execute a request and deliver the response meta and the complete body.
+/// The `stream` flag is kept for guests built before `request_stream` was available.
+fn abi_request(
+    caller: Caller<'_, ControllerCtx>,
+    ptr: u32,
+    size: u32,
+    stream: u32,
+) -> Result<u64, Trap> {
+    let request_value: fn(http::Request<Vec<u8>>) -> AsyncRequestValue = match stream {
+        0 => AsyncRequestValue::Http,
+        _ => |request| AsyncRequestValue::HttpStream(request, None),
+    };
+    start_http_request(caller, ptr, size, request_value)
+}
+
+/// Execute a request and stream the response. The results of the request are, in order:
+///
+/// - the response meta
+/// - every chunk of the body as it is received
+/// - the end of the stream: a finished result without payload,
+///   or an error trailer (finished with an error) if the connection failed
+fn abi_request_stream(caller: Caller<'_, ControllerCtx>, ptr: u32, size: u32) -> Result<u64, Trap> {
+    start_http_request(caller, ptr, size, |request| {
+        AsyncRequestValue::HttpStream(request, None)
+    })
+}
+
+/// Like `request_stream`, but only the watch events that pass the bincode serialized `WatchFilter`
+/// are delivered
+fn abi_request_stream_filtered(
+    mut caller: Caller<'_, ControllerCtx>,
+    ptr: u32,
+    size: u32,
+    filter_ptr: u32,
+    filter_size: u32,
+) -> Result<u64, Trap> {
+    let request = read_http_request(&mut caller, ptr, size)?;
+    let filter = read_guest_bytes(&mut caller, filter_ptr, filter_size)?
+        .ok_or_else(|| "filter is outside of the module memory".to_string())
+        .and_then(|bytes| {
+            bincode::deserialize::<WatchFilter>(&bytes)
+                .map_err(|err| format!("failed to deserialize filter: {}", err))
+        });
+
+    let request_value = request.and_then(|request| {
+        filter.map(|filter| AsyncRequestValue::HttpStream(request, Some(filter)))
+    });
+    submit_request(caller, request_value)
+}
+
+/// Execute a request to a service outside of the cluster, if it is allowed by the outbound HTTP config
+/// of the module. The results are the same as for `request`.
+fn abi_outbound_request(
+    caller: Caller<'_, ControllerCtx>,
+    ptr: u32,
+    size: u32,
+) -> Result<u64, Trap> {
+    start_http_request(caller, ptr, size, AsyncRequestValue::Outbound)
+}
+
+fn start_http_request(
+    mut caller: Caller<'_, ControllerCtx>,
+    ptr: u32,
+    size: u32,
+    request_value: fn(http::Request<Vec<u8>>) -> AsyncRequestValue,
+) -> Result<u64, Trap> {
+    let request_value = read_http_request(&mut caller, ptr, size)?.map(request_value);
+    submit_request(caller, request_value)
+}
+
+/// Read the bincode serialized request at `ptr`, the error is reported to the guest
+fn read_http_request(
+    caller: &mut Caller<'_, ControllerCtx>,
+    ptr: u32,
+    size: u32,
+) -> Result<Result<http::Request<Vec<u8>>, String>, Trap> {
+    Ok(read_guest_bytes(caller, ptr, size)?
+        .ok_or_else(|| "request is outside of the module memory".to_string())
+        .and_then(|bytes| {
+            bincode::deserialize::<HttpRequest<Vec<u8>>>(&bytes)
+                .map(|request| request.into())
+                .map_err(|err| format!("failed to deserialize request: {}", err))
+        }))
+}
+
+fn submit_request(
+    mut caller: Caller<'_, ControllerCtx>,
+    request_value: Result<AsyncRequestValue, String>,
+) -> Result<u64, Trap> {
     let controller_ctx = caller.data_mut();
 
     let async_request_id = controller_ctx
         .async_request_id_counter
         .fetch_add(1, Ordering::SeqCst);
 
-    controller_ctx.ops_runner.lock().unwrap().handle_request(
-        async_request_id,
-        (if stream == 0 {
-            AsyncRequestValue::Http
-        } else {
-            AsyncRequestValue::HttpStream
-        })(inner_request.into()),
-    );
+    let mut ops_runner = controller_ctx.ops_runner.lock().unwrap();
 
-    async_request_id
+    match request_value {
+        Ok(request_value) => ops_runner.handle_request(async_request_id, request_value),
+        // older guests can't receive errors, so the module is stopped like before
+        Err(message) if !controller_ctx.abi_version.supports_error_channel() => {
+            return Err(Trap::new(message))
+        }
+        Err(message) => ops_runner.handle_error(
+            async_request_id,
+            HostError::new(HostErrorKind::InvalidRequest, message),
+        ),
+    }
+
+    Ok(async_request_id)
 }
 
 fn abi_delay(mut caller: Caller<'_, ControllerCtx>, millis: u64) -> u64 {
@@ -134,3 +363,44 @@ fn abi_delay(mut caller: Caller<'_, ControllerCtx>, millis: u64) -> u64 {
 
     async_request_id
 }
+
+fn abi_cancel(mut caller: Caller<'_, ControllerCtx>, async_request_id: u64) {
+    caller
+        .data_mut()
+        .ops_runner
+        .lock()
+        .unwrap()
+        .cancel(async_request_id);
+}
+
+fn abi_register_receive_buffer(
+    mut caller: Caller<'_, ControllerCtx>,
+    ptr: u32,
+    len: u32,
+) -> Result<(), Trap> {
+    if read_guest_bytes(&mut caller, ptr, len)?.is_none() {
+        return Err(Trap::new("receive buffer is outside of the module memory"));
+    }
+
+    tracing::debug!("guest registered a receive buffer of {} bytes", len);
+    caller.data_mut().receive_buffer = Some(ReceiveBuffer { ptr, len });
+
+    Ok(())
+}
+
+fn abi_log(mut caller: Caller<'_, ControllerCtx>, ptr: u32, size: u32) -> Result<(), Trap> {
+    let record_bytes = match read_guest_bytes(&mut caller, ptr, size)? {
+        Some(bytes) => bytes,
+        None => {
+            tracing::warn!("guest log event is outside of the module memory");
+            return Ok(());
+        }
+    };
+
+    match bincode::deserialize::<GuestLogRecord>(&record_bytes) {
+        Ok(record) => record.emit(),
+        Err(err) => tracing::warn!("failed to deserialize guest log event: {}", err),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,18 @@
+/// Memory region registered by the guest to receive results without an `allocate` call per result.
+///
+/// The host writes frames `[async request id][flags][payload length][payload]` (little endian)
+/// into the buffer and calls `wakeup_buffered` with the number of written bytes. The guest copies
+/// the payloads out before returning, so the buffer is reused for every wakeup.
+/// Batches that don't fit in the buffer are passed to `wakeup_batch` in allocated memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ReceiveBuffer {
+    pub ptr: u32,
+    pub len: u32,
+}
+
+pub fn encode_frame(buf: &mut Vec<u8>, async_request_id: u64, flags: u32, payload: &[u8]) {
+    buf.extend_from_slice(&async_request_id.to_le_bytes());
+    buf.extend_from_slice(&flags.to_le_bytes());
+    buf.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    buf.extend_from_slice(payload);
+}
@@ -0,0 +1,153 @@
+use anyhow::Result;
+use std::fmt;
+use std::ops::RangeInclusive;
+
+/// Name of the custom section in which `kube-runtime-abi` stores the ABI version of the guest
+pub const ABI_VERSION_SECTION: &str = "kube-runtime-abi.version";
+
+/// Guests built before the ABI was versioned don't contain the custom section
+pub const LEGACY_ABI_VERSION: AbiVersion = AbiVersion(1);
+
+/// ABI versions this host can run side by side
+pub const SUPPORTED_ABI_VERSIONS: RangeInclusive<AbiVersion> = AbiVersion(1)..=AbiVersion(9);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AbiVersion(pub u32);
+
+impl AbiVersion {
+    /// Since v3 the guest can receive failed results through `wakeup`
+    pub fn supports_error_channel(self) -> bool {
+        self >= AbiVersion(3)
+    }
+
+    /// Since v6 the guest accepts several results in one `wakeup_buffered` or `wakeup_batch` call
+    pub fn supports_batch_wakeup(self) -> bool {
+        self >= AbiVersion(6)
+    }
+}
+
+impl fmt::Display for AbiVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "v{}", self.0)
+    }
+}
+
+/// Determine the ABI version of a guest and check that this host supports it
+pub fn negotiate(wasm_bytes: &[u8]) -> Result<AbiVersion> {
+    let version = read_abi_version(wasm_bytes)?;
+
+    if !SUPPORTED_ABI_VERSIONS.contains(&version) {
+        anyhow::bail!(
+            "module was built against kube-runtime-abi ABI {}, but this host supports {} up to {}",
+            version,
+            SUPPORTED_ABI_VERSIONS.start(),
+            SUPPORTED_ABI_VERSIONS.end()
+        );
+    }
+
+    Ok(version)
+}
+
+/// Read the ABI version from the custom section of a wasm module
+pub fn read_abi_version(wasm_bytes: &[u8]) -> Result<AbiVersion> {
+    if wasm_bytes.len() < 8 || &wasm_bytes[0..4] != b"\0asm" {
+        anyhow::bail!("not a wasm module");
+    }
+
+    let mut offset = 8;
+    while offset < wasm_bytes.len() {
+        let section_id = wasm_bytes[offset];
+        offset += 1;
+
+        let section_size = read_leb128_u32(wasm_bytes, &mut offset)? as usize;
+        let section_end = offset + section_size;
+        if section_end > wasm_bytes.len() {
+            anyhow::bail!("wasm section exceeds the module size");
+        }
+
+        // custom sections have id 0 and start with their name
+        if section_id == 0 {
+            let mut name_offset = offset;
+            let name_size = read_leb128_u32(wasm_bytes, &mut name_offset)? as usize;
+            let name_end = name_offset + name_size;
+
+            if name_end <= section_end
+                && &wasm_bytes[name_offset..name_end] == ABI_VERSION_SECTION.as_bytes()
+            {
+                let payload = &wasm_bytes[name_end..section_end];
+                if payload.len() != 4 {
+                    anyhow::bail!("invalid {} section", ABI_VERSION_SECTION);
+                }
+
+                return Ok(AbiVersion(u32::from_le_bytes([
+                    payload[0], payload[1], payload[2], payload[3],
+                ])));
+            }
+        }
+
+        offset = section_end;
+    }
+
+    Ok(LEGACY_ABI_VERSION)
+}
+
+fn read_leb128_u32(bytes: &[u8], offset: &mut usize) -> Result<u32> {
+    let mut result: u32 = 0;
+    let mut shift = 0;
+
+    loop {
+        let byte = *bytes
+            .get(*offset)
+            .ok_or_else(|| anyhow::anyhow!("unexpected end of wasm module"))?;
+        *offset += 1;
+
+        result |= ((byte & 0x7f) as u32) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(result);
+        }
+
+        shift += 7;
+        if shift >= 35 {
+            anyhow::bail!("invalid LEB128 integer in wasm module");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn module_with_custom_section(name: &str, payload: &[u8]) -> Vec<u8> {
+        let mut module = b"\0asm\x01\0\0\0".to_vec();
+        let section_size = 1 + name.len() + payload.len();
+        module.push(0);
+        module.push(section_size as u8);
+        module.push(name.len() as u8);
+        module.extend_from_slice(name.as_bytes());
+        module.extend_from_slice(payload);
+        module
+    }
+
+    #[test]
+    fn test_read_abi_version() {
+        let module = module_with_custom_section(ABI_VERSION_SECTION, &2u32.to_le_bytes());
+        assert_eq!(read_abi_version(&module).unwrap(), AbiVersion(2));
+    }
+
+    #[test]
+    fn test_read_legacy_abi_version() {
+        let module = module_with_custom_section("name", &[1, 2, 3]);
+        assert_eq!(read_abi_version(&module).unwrap(), LEGACY_ABI_VERSION);
+        assert_eq!(
+            read_abi_version(b"\0asm\x01\0\0\0").unwrap(),
+            LEGACY_ABI_VERSION
+        );
+    }
+
+    #[test]
+    fn test_negotiate_unsupported_version() {
+        let module = module_with_custom_section(ABI_VERSION_SECTION, &99u32.to_le_bytes());
+        assert!(negotiate(&module).is_err());
+        assert!(read_abi_version(b"not wasm").is_err());
+    }
+}
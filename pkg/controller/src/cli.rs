@@ -0,0 +1,237 @@
+use crate::config::{AllocationStrategy, LoggingConfig, RuntimeConfig};
+use crate::logging::LogFormat;
+use clap::{Args, Parser, Subcommand};
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+#[derive(Parser, Debug)]
+#[clap(
+    about = "Parent operator running child operators compiled to WASM",
+    arg_required_else_help = true
+)]
+pub struct Cli {
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+
+    /// Directory containing the wasm_config.yaml and the WASM modules (shorthand for `run <MODULES_DIR>`)
+    pub modules_dir: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub run_options: RunOptions,
+
+    /// Host configuration file (yaml)
+    #[clap(long, global = true, env = "CONTROLLER_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub logging: LoggingArgs,
+}
+
+impl Cli {
+    /// The subcommand to execute, running the modules if only a directory was passed
+    pub fn into_command(self) -> anyhow::Result<Command> {
+        match (self.command, self.modules_dir) {
+            (Some(command), _) => Ok(command),
+            (None, Some(modules_dir)) => Ok(Command::Run(RunArgs {
+                modules_dir,
+                options: self.run_options,
+            })),
+            (None, None) => Err(anyhow::anyhow!(
+                "Usage: controller [run] <MODULES_DIR>, see --help for the other commands"
+            )),
+        }
+    }
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Run the child operators configured in a modules directory
+    Run(RunArgs),
+    /// Compile the child operators ahead of time and store them in the cache
+    Precompile(PrecompileArgs),
+    /// Check the host config and the modules config without starting anything
+    ValidateConfig(ValidateConfigArgs),
+    /// Print information about the memory snapshot of a swapped out module
+    InspectSnapshot(InspectSnapshotArgs),
+}
+
+#[derive(Args, Debug)]
+pub struct RunArgs {
+    /// Directory containing the wasm_config.yaml and the WASM modules
+    pub modules_dir: PathBuf,
+
+    #[clap(flatten)]
+    pub options: RunOptions,
+}
+
+#[derive(Args, Debug)]
+pub struct RunOptions {
+    #[clap(flatten)]
+    pub cache: CacheArgs,
+
+    /// Directory where the memory of swapped out modules is written to [default: <TMP>/swap]
+    #[clap(long, env = "CONTROLLER_SWAP_DIR")]
+    pub swap_dir: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub runtime: RuntimeArgs,
+
+    #[clap(flatten)]
+    pub api_server: ApiServerArgs,
+
+    /// Serve Prometheus metrics on this address (e.g. 0.0.0.0:9090)
+    #[clap(long, env = "CONTROLLER_METRICS_ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Write every Kubernetes API request and response to this file (JSON lines), to replay them later
+    #[clap(long, conflicts_with = "replay")]
+    pub record: Option<PathBuf>,
+
+    /// Answer the Kubernetes API requests with the responses recorded in this file, without a cluster
+    #[clap(long)]
+    pub replay: Option<PathBuf>,
+}
+
+impl RunOptions {
+    pub fn swap_dir(&self) -> PathBuf {
+        self.swap_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("swap"))
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct RuntimeArgs {
+    /// Allocation strategy of the instances: pooling or on-demand
+    #[clap(long, env = "CONTROLLER_ALLOCATION")]
+    pub allocation: Option<AllocationStrategy>,
+
+    /// Maximum number of modules that are instantiated at the same time
+    #[clap(long, env = "CONTROLLER_POOL_SIZE")]
+    pub pool_size: Option<u32>,
+
+    /// Swap out the memory of inactive modules to disk: true or false
+    #[clap(long, env = "CONTROLLER_SWAPPING")]
+    pub swapping: Option<bool>,
+
+    /// Capacity of the async result queue of every module
+    #[clap(long, env = "CONTROLLER_RESULT_QUEUE_SIZE")]
+    pub result_queue_size: Option<usize>,
+
+    /// Capacity of the runtime command queue
+    #[clap(long, env = "CONTROLLER_COMMAND_QUEUE_SIZE")]
+    pub command_queue_size: Option<usize>,
+}
+
+impl RuntimeArgs {
+    /// Apply the runtime flags on top of the configuration file
+    pub fn apply(&self, config: &mut RuntimeConfig) {
+        if let Some(allocation) = self.allocation {
+            config.allocation = allocation;
+        }
+        if let Some(pool_size) = self.pool_size {
+            config.pool_size = Some(pool_size);
+        }
+        if let Some(swapping) = self.swapping {
+            config.swapping = swapping;
+        }
+        if let Some(result_queue_size) = self.result_queue_size {
+            config.result_queue_size = result_queue_size;
+        }
+        if let Some(command_queue_size) = self.command_queue_size {
+            config.command_queue_size = command_queue_size;
+        }
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct CacheArgs {
+    /// Directory where the precompiled modules are stored [default: <TMP>/cache]
+    #[clap(long, env = "CONTROLLER_CACHE_DIR")]
+    pub cache_dir: Option<PathBuf>,
+}
+
+impl CacheArgs {
+    pub fn cache_dir(&self) -> PathBuf {
+        self.cache_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("cache"))
+    }
+}
+
+#[derive(Args, Debug)]
+pub struct ApiServerArgs {
+    /// Use this kubeconfig context instead of the current one
+    #[clap(long)]
+    pub kube_context: Option<String>,
+
+    /// Override the url of the Kubernetes API server
+    #[clap(long)]
+    pub server_url: Option<http::Uri>,
+}
+
+#[derive(Args, Debug)]
+pub struct PrecompileArgs {
+    /// Directory containing the wasm_config.yaml and the WASM modules
+    pub modules_dir: PathBuf,
+
+    #[clap(flatten)]
+    pub cache: CacheArgs,
+
+    /// Remove the cache entries that are not used by the modules
+    #[clap(long)]
+    pub gc: bool,
+}
+
+#[derive(Args, Debug)]
+pub struct ValidateConfigArgs {
+    /// Directory containing the wasm_config.yaml and the WASM modules
+    pub modules_dir: Option<PathBuf>,
+
+    #[clap(flatten)]
+    pub runtime: RuntimeArgs,
+}
+
+#[derive(Args, Debug)]
+pub struct InspectSnapshotArgs {
+    /// Swap file of a module (e.g. <SWAP_DIR>/worker_0_mem.bin)
+    pub snapshot: PathBuf,
+}
+
+#[derive(Args, Debug)]
+pub struct LoggingArgs {
+    /// Default log level (e.g. info, debug), overrides RUST_LOG and the config file
+    #[clap(long, global = true)]
+    pub log_level: Option<String>,
+
+    /// Log filter for a specific target (e.g. hyper=warn), can be repeated
+    #[clap(long = "log-filter", global = true)]
+    pub log_filters: Vec<String>,
+
+    /// Output format of the logs: text or json
+    #[clap(long, global = true, env = "LOG_FORMAT")]
+    pub log_format: Option<LogFormat>,
+}
+
+impl LoggingArgs {
+    /// Apply the logging flags on top of the configuration file.
+    /// An existing `RUST_LOG` takes precedence over the config file, but not over the flags.
+    pub fn apply(&self, config: &mut LoggingConfig) {
+        if let Some(format) = self.log_format {
+            config.format = format;
+        }
+
+        if self.log_level.is_none() && self.log_filters.is_empty() {
+            if let Ok(rust_log) = std::env::var("RUST_LOG") {
+                config.level = rust_log;
+                config.filters.clear();
+            }
+            return;
+        }
+
+        if let Some(level) = &self.log_level {
+            config.level = level.clone();
+        }
+        config.filters.extend(self.log_filters.iter().cloned());
+    }
+}
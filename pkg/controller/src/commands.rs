@@ -0,0 +1,338 @@
+use crate::abi::version;
+use crate::cli::{ApiServerArgs, InspectSnapshotArgs, PrecompileArgs, RunArgs, ValidateConfigArgs};
+use crate::config::{
+    AllocationStrategy, ClusterConfig, HostConfig, RuntimeConfig, DEFAULT_CLUSTER,
+};
+use crate::kube_client::{ClientMode, Recorder, Recording};
+use crate::metrics::{self, Metrics};
+use crate::modules::{ControllerModuleMetadata, WASM_PAGE_SIZE};
+use crate::runtime;
+use crate::runtime::http_engine::outbound::OutboundClient;
+use crate::runtime::{ClusterRegistry, Environment, ModuleCache};
+use anyhow::{Context, Result};
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use kube::Config;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{error, info};
+
+/// Infer the kubeconfig, taking into account the context and api server overrides
+async fn load_kubeconfig(args: &ApiServerArgs) -> Result<Config> {
+    let mut kubeconfig = match &args.kube_context {
+        None => Config::infer().await?,
+        Some(context) => {
+            Config::from_kubeconfig(&KubeConfigOptions {
+                context: Some(context.clone()),
+                ..Default::default()
+            })
+            .await?
+        }
+    };
+
+    if let Some(server_url) = &args.server_url {
+        kubeconfig.cluster_url = server_url.clone();
+    }
+
+    Ok(kubeconfig)
+}
+
+/// Load the kubeconfig of a named cluster of the host config
+async fn load_cluster_kubeconfig(cluster: &ClusterConfig) -> Result<Config> {
+    let options = KubeConfigOptions {
+        context: cluster.context.clone(),
+        ..Default::default()
+    };
+    let mut kubeconfig = match &cluster.kubeconfig {
+        None => Config::from_kubeconfig(&options).await?,
+        Some(path) => {
+            Config::from_custom_kubeconfig(Kubeconfig::read_from(path)?, &options).await?
+        }
+    };
+
+    if let Some(server_url) = &cluster.server_url {
+        kubeconfig.cluster_url = server_url.parse()?;
+    }
+
+    Ok(kubeconfig)
+}
+
+/// Requests are answered by the recording, they never reach this url
+fn replay_kubeconfig() -> Config {
+    Config::new(http::Uri::from_static("https://replay.invalid"))
+}
+
+pub fn run(host_config: &HostConfig, args: RunArgs) -> Result<()> {
+    // Bootstrap tokio runtime and kube-rs-async config/client
+    let runtime = tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .context("Cannot create a tokio runtime")?;
+
+    // a replay does not talk to a cluster, so it does not need a kubeconfig
+    let mode = match (&args.options.record, &args.options.replay) {
+        (_, Some(replay)) => ClientMode::Replay(Arc::new(Recording::load(replay)?)),
+        (Some(record), None) => ClientMode::Record(Arc::new(Recorder::create(record)?)),
+        (None, None) => ClientMode::Live,
+    };
+    let is_replay = matches!(mode, ClientMode::Replay(_));
+
+    let kubeconfig = if is_replay {
+        replay_kubeconfig()
+    } else {
+        runtime
+            .block_on(load_kubeconfig(&args.options.api_server))
+            .context("Cannot infer the kubeconfig")?
+    };
+
+    host_config
+        .client
+        .validate()
+        .context("Invalid client config")?;
+    host_config
+        .validate_clusters()
+        .context("Invalid cluster config")?;
+
+    let mut kubeconfigs = vec![(DEFAULT_CLUSTER.to_string(), kubeconfig)];
+    for (name, cluster) in host_config.clusters.iter() {
+        let kubeconfig = if is_replay {
+            replay_kubeconfig()
+        } else {
+            runtime
+                .block_on(load_cluster_kubeconfig(cluster))
+                .with_context(|| format!("Cannot load the kubeconfig of cluster {}", name))?
+        };
+        kubeconfigs.push((name.clone(), kubeconfig));
+    }
+
+    let path = args.modules_dir;
+    info!("Going to load from {}", path.display());
+
+    let cache_path = args.options.cache.cache_dir();
+
+    let swap_path = args.options.swap_dir();
+    std::fs::create_dir_all(&swap_path)?;
+
+    let mods = ControllerModuleMetadata::load_modules_from_dir(path)
+        .context("Cannot load the modules from the provided dir")?;
+
+    let mut runtime_config = host_config.runtime.clone();
+    args.options.runtime.apply(&mut runtime_config);
+    runtime_config
+        .validate(mods.len())
+        .context("Invalid runtime config")?;
+
+    let clusters = runtime
+        .block_on(ClusterRegistry::new(
+            kubeconfigs,
+            &host_config.client,
+            &runtime_config,
+            mode,
+        ))
+        .context("could not setup kube client")?;
+
+    let metrics = Arc::new(Metrics::new(
+        runtime_config.command_queue_size,
+        runtime_config.result_queue_size,
+    ));
+
+    runtime.block_on(async {
+        let (runtime_command_sender, runtime_command_receiver) = metrics::metered_channel(
+            "commands",
+            runtime_config.command_queue_size,
+            metrics.command_queue(),
+        );
+
+        if let Some(metrics_addr) = args.options.metrics_addr {
+            let metrics = metrics.clone();
+            tokio::spawn(async move {
+                if let Err(err) = metrics::serve(metrics_addr, metrics).await {
+                    error!("metrics endpoint failed: {:?}", err);
+                }
+            });
+        }
+
+        tokio::spawn(runtime::start(
+            runtime_command_receiver,
+            clusters,
+            cache_path,
+            swap_path,
+            runtime_config,
+            metrics,
+        ));
+
+        tokio::spawn(async move {
+            for module_metadata in mods {
+                runtime_command_sender
+                    .send(runtime::Command::StartModule(module_metadata))
+                    .await
+                    .map_err(|e| anyhow::anyhow!("{}", e))
+                    .unwrap();
+            }
+        });
+
+        tokio::signal::ctrl_c().await?;
+        info!("Closing");
+
+        Ok::<(), anyhow::Error>(())
+    })
+}
+
+pub fn precompile(args: PrecompileArgs) -> Result<()> {
+    let mods = ControllerModuleMetadata::load_modules_from_dir(args.modules_dir)
+        .context("Cannot load the modules from the provided dir")?;
+
+    // the allocation strategy is not part of the fingerprint, so no memory has to be reserved
+    let environment = Environment::new(&RuntimeConfig {
+        allocation: AllocationStrategy::OnDemand,
+        pool_size: Some(1),
+        swapping: false,
+        ..Default::default()
+    })?;
+    let module_cache = ModuleCache::new(args.cache.cache_dir(), &environment)?;
+
+    let mut used_entries = HashSet::new();
+    for module_metadata in mods {
+        let start = Instant::now();
+        let serialized_wasm_path = module_cache
+            .get_or_compile(&environment, &module_metadata.wasm)
+            .with_context(|| format!("precompiling {} failed", module_metadata.name))?;
+
+        info!(
+            "precompiled {} into {} in {:?}",
+            module_metadata.name,
+            serialized_wasm_path.display(),
+            start.elapsed()
+        );
+        used_entries.insert(serialized_wasm_path);
+    }
+
+    println!(
+        "cache: {} hits, {} compiled",
+        module_cache.hits(),
+        module_cache.misses()
+    );
+
+    if args.gc {
+        let removed = module_cache.gc(&used_entries)?;
+        println!("cache: removed {} unused entries", removed);
+    }
+
+    Ok(())
+}
+
+pub fn validate_config(host_config: &HostConfig, args: ValidateConfigArgs) -> Result<()> {
+    let mut runtime_config = host_config.runtime.clone();
+    args.runtime.apply(&mut runtime_config);
+
+    host_config
+        .client
+        .validate()
+        .context("Invalid client config")?;
+    host_config
+        .validate_clusters()
+        .context("Invalid cluster config")?;
+
+    let modules_dir = match args.modules_dir {
+        None => {
+            runtime_config
+                .validate(0)
+                .context("Invalid runtime config")?;
+            println!("host config: ok");
+            return Ok(());
+        }
+        Some(modules_dir) => modules_dir,
+    };
+
+    let mods = ControllerModuleMetadata::load_modules_from_dir(modules_dir)
+        .context("Cannot load the modules from the provided dir")?;
+
+    let mut names = HashSet::new();
+    for module_metadata in mods.iter() {
+        if !names.insert(module_metadata.name.as_str()) {
+            anyhow::bail!(
+                "module name {} is used more than once",
+                module_metadata.name
+            );
+        }
+
+        if !module_metadata.wasm.is_file() {
+            anyhow::bail!(
+                "wasm file {} of module {} does not exist",
+                module_metadata.wasm.display(),
+                module_metadata.name
+            );
+        }
+
+        let wasm_bytes = std::fs::read(&module_metadata.wasm)?;
+        let abi_version = version::negotiate(&wasm_bytes)
+            .with_context(|| format!("module {} can not be loaded", module_metadata.name))?;
+
+        if let Some(identity) = &module_metadata.identity {
+            identity
+                .validate()
+                .and_then(|_| identity.impersonation_headers().map(|_| ()))
+                .with_context(|| format!("invalid identity of module {}", module_metadata.name))?;
+        }
+
+        if let Some(cluster) = &module_metadata.cluster {
+            if cluster != DEFAULT_CLUSTER && !host_config.clusters.contains_key(cluster) {
+                anyhow::bail!(
+                    "cluster {} of module {} is not in the host config",
+                    cluster,
+                    module_metadata.name
+                );
+            }
+        }
+
+        if let Some(policy) = &module_metadata.policy {
+            policy
+                .validate()
+                .with_context(|| format!("invalid policy of module {}", module_metadata.name))?;
+        }
+
+        if let Some(outbound_http) = &module_metadata.outbound_http {
+            OutboundClient::new(outbound_http).with_context(|| {
+                format!(
+                    "invalid outbound HTTP config of module {}",
+                    module_metadata.name
+                )
+            })?;
+        }
+
+        println!("module {}: ok (ABI {})", module_metadata.name, abi_version);
+    }
+
+    runtime_config
+        .validate(mods.len())
+        .context("Invalid runtime config")?;
+    println!("host config: ok");
+
+    Ok(())
+}
+
+pub fn inspect_snapshot(args: InspectSnapshotArgs) -> Result<()> {
+    let memory = std::fs::read(&args.snapshot)
+        .with_context(|| format!("failed to read snapshot {}", args.snapshot.display()))?;
+
+    let pages = memory.chunks(WASM_PAGE_SIZE as usize);
+    let total_pages = pages.len();
+    let zero_pages = pages
+        .filter(|page| page.iter().all(|byte| *byte == 0))
+        .count();
+
+    println!("snapshot:    {}", args.snapshot.display());
+    println!("memory size: {} bytes", memory.len());
+    println!("wasm pages:  {}", total_pages);
+    println!(
+        "zero pages:  {} ({:.1}%)",
+        zero_pages,
+        if total_pages == 0 {
+            0.0
+        } else {
+            zero_pages as f64 * 100.0 / total_pages as f64
+        }
+    );
+
+    Ok(())
+}
@@ -0,0 +1,299 @@
+use crate::logging::LogFormat;
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// Configuration of the host (parent operator), loaded from a yaml file
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct HostConfig {
+    pub logging: LoggingConfig,
+    pub runtime: RuntimeConfig,
+    pub client: ClientConfig,
+    /// Clusters modules can manage besides the default one, by name
+    pub clusters: BTreeMap<String, ClusterConfig>,
+}
+
+/// Name of the cluster of the kubeconfig the host is started with, used by modules without a `cluster`
+pub const DEFAULT_CLUSTER: &str = "default";
+
+/// A cluster modules can select with the `cluster` field of their metadata
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ClusterConfig {
+    /// Kubeconfig file, the default kubeconfig (`KUBECONFIG` or `~/.kube/config`) if not set
+    pub kubeconfig: Option<PathBuf>,
+    /// Context of the kubeconfig, its current context if not set
+    pub context: Option<String>,
+    /// Override the url of the Kubernetes API server
+    pub server_url: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct LoggingConfig {
+    /// Default level for all targets
+    pub level: String,
+    /// Per target filters, using the `RUST_LOG` directive syntax (e.g. `hyper=warn`)
+    pub filters: Vec<String>,
+    pub format: LogFormat,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "debug".to_string(),
+            filters: vec![
+                "tower=warn".to_string(),
+                "rustls=warn".to_string(),
+                "wasmtime_cranelift=warn".to_string(),
+                "cranelift=warn".to_string(),
+                "regalloc=warn".to_string(),
+                "hyper=warn".to_string(),
+            ],
+            format: LogFormat::Text,
+        }
+    }
+}
+
+impl LoggingConfig {
+    /// The filter directives in the `RUST_LOG` syntax
+    pub fn directives(&self) -> String {
+        std::iter::once(self.level.clone())
+            .chain(self.filters.iter().cloned())
+            .collect::<Vec<String>>()
+            .join(",")
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AllocationStrategy {
+    /// Allocate the memory of an instance when it is instantiated
+    OnDemand,
+    /// Preallocate the memory of `pool_size` instances and reuse it
+    Pooling,
+}
+
+impl FromStr for AllocationStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "on-demand" => Ok(AllocationStrategy::OnDemand),
+            "pooling" => Ok(AllocationStrategy::Pooling),
+            other => Err(anyhow::anyhow!("unknown allocation strategy '{}'", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct RuntimeConfig {
+    pub allocation: AllocationStrategy,
+    /// Maximum number of modules that are instantiated at the same time
+    pub pool_size: Option<u32>,
+    /// Write the memory of inactive modules to disk and release their instance
+    pub swapping: bool,
+    /// Capacity of the queue of async results of a module, requests block when it is full
+    pub result_queue_size: usize,
+    /// Capacity of the queue of runtime commands (e.g. starting a module)
+    pub command_queue_size: usize,
+    /// Number of events of a shared watch kept, so modules can join it from an older resource version.
+    /// 0 disables sharing watches between modules
+    pub watch_history_size: usize,
+}
+
+impl Default for RuntimeConfig {
+    fn default() -> Self {
+        Self {
+            allocation: AllocationStrategy::Pooling,
+            pool_size: None,
+            swapping: true,
+            result_queue_size: 10,
+            command_queue_size: 10,
+            watch_history_size: 100,
+        }
+    }
+}
+
+impl RuntimeConfig {
+    pub fn pool_size(&self) -> u32 {
+        self.pool_size.unwrap_or(match self.allocation {
+            // TODO: why set pool size to 10?
+            AllocationStrategy::Pooling => 100,
+            AllocationStrategy::OnDemand => 1000,
+        })
+    }
+
+    /// Reject combinations of settings that can not work for the given number of modules
+    pub fn validate(&self, nr_modules: usize) -> Result<()> {
+        if self.pool_size() == 0 {
+            anyhow::bail!("the pool size must be at least 1");
+        }
+
+        if self.result_queue_size == 0 || self.command_queue_size == 0 {
+            anyhow::bail!("the queue sizes must be at least 1");
+        }
+
+        // re-instantiating a swapped out module is only cheap when its memory slot can be reused
+        if self.swapping && self.allocation == AllocationStrategy::OnDemand {
+            anyhow::bail!("swapping requires the pooling allocation strategy");
+        }
+
+        // without swapping, an instantiated module never gives back its place in the pool
+        if !self.swapping && (self.pool_size() as usize) < nr_modules {
+            anyhow::bail!(
+                "{} modules do not fit in a pool of size {} without swapping",
+                nr_modules,
+                self.pool_size()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+/// Timeouts and retries of the Kubernetes API client, a timeout of `null` disables it
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct ClientConfig {
+    pub connect_timeout_ms: Option<u64>,
+    /// Maximum time without receiving data on a connection
+    pub read_timeout_ms: Option<u64>,
+    pub write_timeout_ms: Option<u64>,
+    /// Read timeout of watches and followed logs, which can stay idle for minutes
+    pub watch_read_timeout_ms: Option<u64>,
+    /// Number of times a request with an idempotent verb is retried after a connection error or a 502, 503 or 504,
+    /// or after a 429 of which the `Retry-After` is at most `retry_max_delay_ms`
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled for every next retry and randomized by up to half
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    /// Limit of all requests to the API server, `null` for no limit
+    pub rate_limit: Option<RateLimitConfig>,
+    /// Limit of the requests of a module that does not configure its own `rateLimit`
+    pub module_rate_limit: Option<RateLimitConfig>,
+    /// Requests of a module that can wait for its rate limit, more are answered with a 429
+    pub module_queue_size: usize,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            connect_timeout_ms: Some(10_000),
+            read_timeout_ms: Some(60_000),
+            write_timeout_ms: Some(60_000),
+            // longer than the 290 seconds after which kube-rs restarts a watch
+            watch_read_timeout_ms: Some(330_000),
+            max_retries: 3,
+            retry_base_delay_ms: 100,
+            retry_max_delay_ms: 5_000,
+            rate_limit: Some(RateLimitConfig {
+                qps: 100.0,
+                burst: 200,
+            }),
+            module_rate_limit: Some(RateLimitConfig {
+                qps: 20.0,
+                burst: 40,
+            }),
+            module_queue_size: 100,
+        }
+    }
+}
+
+impl ClientConfig {
+    pub fn validate(&self) -> Result<()> {
+        let timeouts = [
+            self.connect_timeout_ms,
+            self.read_timeout_ms,
+            self.write_timeout_ms,
+            self.watch_read_timeout_ms,
+        ];
+        if timeouts.contains(&Some(0)) {
+            anyhow::bail!("client timeouts must be at least 1 ms, use null to disable a timeout");
+        }
+
+        if self.retry_base_delay_ms > self.retry_max_delay_ms {
+            anyhow::bail!("the retry base delay can not be larger than the maximum delay");
+        }
+
+        if self.module_queue_size == 0 {
+            anyhow::bail!("the module queue size must be at least 1");
+        }
+
+        for rate_limit in self.rate_limit.iter().chain(&self.module_rate_limit) {
+            rate_limit.validate()?;
+        }
+
+        Ok(())
+    }
+}
+
+// bounds how long a request waits for the next token of a rate limit
+const MIN_QPS: f64 = 1.0 / 3600.0;
+
+/// Token bucket of `burst` requests, refilled at `qps` requests per second
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+    pub qps: f64,
+    pub burst: u64,
+}
+
+impl RateLimitConfig {
+    pub fn validate(&self) -> Result<()> {
+        if !self.qps.is_finite() || self.qps <= 0.0 || self.burst == 0 {
+            anyhow::bail!("a rate limit must have a qps larger than 0 and a burst of at least 1");
+        }
+        if self.qps < MIN_QPS {
+            anyhow::bail!("a rate limit must allow at least one request per hour");
+        }
+
+        Ok(())
+    }
+
+    /// The rate limit scaled by a factor, keeping at least a burst of 1
+    pub fn share(&self, factor: f64) -> Self {
+        Self {
+            qps: self.qps * factor,
+            burst: ((self.burst as f64 * factor).ceil() as u64).max(1),
+        }
+    }
+}
+
+impl HostConfig {
+    pub fn validate_clusters(&self) -> Result<()> {
+        if self.clusters.contains_key(DEFAULT_CLUSTER) {
+            anyhow::bail!(
+                "the cluster name {} is reserved for the cluster the host is started with",
+                DEFAULT_CLUSTER
+            );
+        }
+
+        for (name, cluster) in self.clusters.iter() {
+            if let Some(server_url) = &cluster.server_url {
+                server_url
+                    .parse::<http::Uri>()
+                    .with_context(|| format!("invalid server url of cluster {}", name))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load the host config from a yaml file, or use the defaults if no file is provided
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        match path {
+            None => Ok(HostConfig::default()),
+            Some(path) => {
+                let config = std::fs::read_to_string(path)
+                    .with_context(|| format!("failed to read host config {}", path.display()))?;
+
+                serde_yaml::from_str(&config)
+                    .map_err(|e| anyhow::anyhow!("Failed to parse host config: {}", e))
+            }
+        }
+    }
+}
@@ -0,0 +1,289 @@
+use super::retry::clone_request;
+use anyhow::{Context, Result};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use http::{header, HeaderValue, Request, Response, StatusCode};
+use hyper::Body;
+use kube::config::{AuthInfo, ExecConfig};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, Instant};
+use tower::{BoxError, Layer, Service, ServiceExt};
+use tracing::{debug, warn};
+
+// projected ServiceAccount tokens are rotated well before they expire, client-go reads them every minute as well
+const TOKEN_FILE_REFRESH: Duration = Duration::from_secs(60);
+// refresh the token of a credential plugin before requests with it can be rejected
+const EXPIRY_MARGIN: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+struct ExecCredential {
+    status: Option<ExecCredentialStatus>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExecCredentialStatus {
+    token: Option<String>,
+    expiration_timestamp: Option<String>,
+}
+
+enum TokenSource {
+    File(PathBuf),
+    /// A client-go credential plugin, only plugins that return a token are supported
+    Exec(ExecConfig),
+}
+
+struct Token {
+    header: HeaderValue,
+    /// `None` if the token does not expire
+    refresh_at: Option<Instant>,
+}
+
+/// Bearer token that is read again from its source before it expires, or after the API server rejected it
+pub(crate) struct RefreshingToken {
+    source: TokenSource,
+    token: tokio::sync::Mutex<Option<Token>>,
+}
+
+impl RefreshingToken {
+    /// `None` if the credentials of the kubeconfig do not expire, kube-rs handles those
+    pub(crate) fn new(auth_info: &AuthInfo) -> Option<Self> {
+        let source = match (&auth_info.token, &auth_info.token_file, &auth_info.exec) {
+            (Some(_), _, _) => return None,
+            (None, Some(token_file), _) => TokenSource::File(PathBuf::from(token_file)),
+            (None, None, Some(exec)) => TokenSource::Exec(exec.clone()),
+            (None, None, None) => return None,
+        };
+
+        Some(Self {
+            source,
+            token: tokio::sync::Mutex::new(None),
+        })
+    }
+
+    /// The `Authorization` header, concurrent requests wait for a single refresh
+    async fn header(&self) -> Result<HeaderValue> {
+        let mut token = self.token.lock().await;
+        if let Some(token) = token.as_ref() {
+            if token.refresh_at.map_or(true, |at| Instant::now() < at) {
+                return Ok(token.header.clone());
+            }
+        }
+
+        let (value, refresh_at) = match &self.source {
+            TokenSource::File(path) => {
+                let value = tokio::fs::read_to_string(path)
+                    .await
+                    .with_context(|| format!("failed to read token file {}", path.display()))?;
+                (value, Some(Instant::now() + TOKEN_FILE_REFRESH))
+            }
+            TokenSource::Exec(exec) => {
+                let (value, expires) = run_credential_plugin(exec).await?;
+                let refresh_at = expires.map(|expires| {
+                    expires
+                        .checked_sub(EXPIRY_MARGIN)
+                        .unwrap_or_else(Instant::now)
+                });
+                (value, refresh_at)
+            }
+        };
+        debug!("refreshed the bearer token");
+
+        let mut header = HeaderValue::from_str(&format!("Bearer {}", value.trim()))
+            .context("the bearer token is not a valid header value")?;
+        header.set_sensitive(true);
+        *token = Some(Token {
+            header: header.clone(),
+            refresh_at,
+        });
+
+        Ok(header)
+    }
+
+    /// Forget a rejected token, unless another request already replaced it
+    async fn invalidate(&self, header: &HeaderValue) {
+        let mut token = self.token.lock().await;
+        if token
+            .as_ref()
+            .map_or(false, |token| token.header == *header)
+        {
+            *token = None;
+        }
+    }
+}
+
+/// Run a credential plugin, returns its token and when that expires
+async fn run_credential_plugin(exec: &ExecConfig) -> Result<(String, Option<Instant>)> {
+    let exec_info = serde_json::json!({
+        "apiVersion": exec.api_version.as_deref().unwrap_or("client.authentication.k8s.io/v1beta1"),
+        "kind": "ExecCredential",
+        "spec": { "interactive": false },
+    });
+
+    let mut command = tokio::process::Command::new(&exec.command);
+    command
+        .args(exec.args.iter().flatten())
+        .env("KUBERNETES_EXEC_INFO", exec_info.to_string())
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit());
+    for env in exec.env.iter().flatten() {
+        if let (Some(name), Some(value)) = (env.get("name"), env.get("value")) {
+            command.env(name, value);
+        }
+    }
+
+    let output = command
+        .output()
+        .await
+        .with_context(|| format!("failed to run credential plugin {}", exec.command))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "credential plugin {} failed with {}",
+            exec.command,
+            output.status
+        );
+    }
+
+    let status = serde_json::from_slice::<ExecCredential>(&output.stdout)
+        .with_context(|| {
+            format!(
+                "invalid ExecCredential of credential plugin {}",
+                exec.command
+            )
+        })?
+        .status
+        .context("the ExecCredential has no status")?;
+    let token = status
+        .token
+        .context("the ExecCredential has no token, client certificates are not supported")?;
+
+    let expires = match status.expiration_timestamp {
+        None => None,
+        Some(timestamp) => {
+            let expires = chrono::DateTime::parse_from_rfc3339(&timestamp)
+                .with_context(|| format!("invalid expirationTimestamp {}", timestamp))?;
+            let remaining = (expires.with_timezone(&chrono::Utc) - chrono::Utc::now())
+                .to_std()
+                .unwrap_or_default();
+            Some(Instant::now() + remaining)
+        }
+    };
+
+    Ok((token, expires))
+}
+
+/// Authenticate requests with a `RefreshingToken`
+#[derive(Clone)]
+pub(crate) struct RefreshAuthLayer(Arc<RefreshingToken>);
+
+impl RefreshAuthLayer {
+    pub(crate) fn new(token: RefreshingToken) -> Self {
+        Self(Arc::new(token))
+    }
+}
+
+impl<S> Layer<S> for RefreshAuthLayer {
+    type Service = RefreshAuth<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RefreshAuth {
+            inner,
+            token: self.0.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct RefreshAuth<S> {
+    inner: S,
+    token: Arc<RefreshingToken>,
+}
+
+fn authorize(request: &mut Request<Body>, header: HeaderValue) {
+    request.headers_mut().insert(header::AUTHORIZATION, header);
+}
+
+impl<S, B> Service<Request<Body>> for RefreshAuth<S>
+where
+    S: Service<Request<Body>, Response = Response<B>> + Clone + Send + 'static,
+    S::Future: Send,
+    S::Error: Into<BoxError>,
+    B: Send + 'static,
+{
+    type Response = Response<B>;
+    type Error = BoxError;
+    type Future = BoxFuture<'static, Result<Response<B>, BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx).map_err(Into::into)
+    }
+
+    fn call(&mut self, request: Request<Body>) -> Self::Future {
+        // the polled service is used for this request, its clone for the next one
+        let clone = self.inner.clone();
+        let inner = std::mem::replace(&mut self.inner, clone);
+
+        send_authorized(inner, self.token.clone(), request).boxed()
+    }
+}
+
+async fn send_authorized<S, B>(
+    mut inner: S,
+    token: Arc<RefreshingToken>,
+    mut request: Request<Body>,
+) -> Result<Response<B>, BoxError>
+where
+    S: Service<Request<Body>, Response = Response<B>>,
+    S::Error: Into<BoxError>,
+{
+    let retry = clone_request(&request);
+    let header = token.header().await?;
+    authorize(&mut request, header.clone());
+
+    let response = inner.call(request).await.map_err(Into::<BoxError>::into)?;
+    match retry {
+        // the token was revoked or rotated before we expected it
+        Some(mut retry) if response.status() == StatusCode::UNAUTHORIZED => {
+            warn!("the API server rejected the bearer token, refreshing it");
+            token.invalidate(&header).await;
+            authorize(&mut retry, token.header().await?);
+
+            let inner = inner.ready().await.map_err(Into::<BoxError>::into)?;
+            inner.call(retry).await.map_err(Into::into)
+        }
+        _ => Ok(response),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_file_rotation() {
+        let path = std::env::temp_dir().join(format!("token-{}", std::process::id()));
+        std::fs::write(&path, "first\n").unwrap();
+
+        let token = RefreshingToken::new(&AuthInfo {
+            token_file: Some(path.display().to_string()),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let first = token.header().await.unwrap();
+        assert_eq!(first, "Bearer first");
+
+        // the cached token is used until it is refreshed or rejected
+        std::fs::write(&path, "second\n").unwrap();
+        assert_eq!(token.header().await.unwrap(), "Bearer first");
+
+        token.invalidate(&first).await;
+        assert_eq!(token.header().await.unwrap(), "Bearer second");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+use super::KubeClientService;
+use crate::config::{ClientConfig, RateLimitConfig};
+use crate::modules::Priority;
+use anyhow::Context;
+use futures::FutureExt;
+use http::{header, Request, Response, StatusCode};
+use hyper::Body;
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+use tokio::time::{Instant, Sleep};
+use tower::load_shed::{error::Overloaded, LoadShed};
+use tower::{buffer::Buffer, util::BoxService, BoxError, Service, ServiceExt};
+use tracing::warn;
+
+// requests of all modules can wait for the shared limits
+const SHARED_QUEUE_SIZE: usize = 1024;
+
+/// Starts with `burst` tokens and adds `qps` tokens per second, up to `burst` again
+struct TokenBucket {
+    qps: f64,
+    burst: f64,
+    tokens: f64,
+    refilled: Instant,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl TokenBucket {
+    fn new(rate_limit: &RateLimitConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            qps: rate_limit.qps,
+            burst: rate_limit.burst as f64,
+            tokens: rate_limit.burst as f64,
+            refilled: now,
+            sleep: Box::pin(tokio::time::sleep_until(now)),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.qps).min(self.burst);
+        self.refilled = now;
+    }
+}
+
+impl Service<()> for TokenBucket {
+    type Response = ();
+    type Error = BoxError;
+    type Future = futures::future::Ready<Result<(), BoxError>>;
+
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        loop {
+            self.refill();
+            if self.tokens >= 1.0 {
+                return Poll::Ready(Ok(()));
+            }
+
+            // the qps of a valid rate limit keeps this far from overflowing a `Duration`
+            let wait = Duration::from_secs_f64((1.0 - self.tokens) / self.qps);
+            self.sleep.as_mut().reset(self.refilled + wait);
+            if self.sleep.as_mut().poll(cx).is_pending() {
+                return Poll::Pending;
+            }
+        }
+    }
+
+    fn call(&mut self, (): ()) -> Self::Future {
+        self.tokens -= 1.0;
+        futures::future::ready(Ok(()))
+    }
+}
+
+/// Hands out a token of its rate limit for every call
+type Gate = Buffer<BoxService<(), (), BoxError>, ()>;
+
+fn gate(rate_limit: &RateLimitConfig, queue_size: usize) -> Gate {
+    Buffer::new(BoxService::new(TokenBucket::new(rate_limit)), queue_size)
+}
+
+fn share(priority: Priority) -> f64 {
+    match priority {
+        Priority::Low => 0.5,
+        Priority::Normal => 0.8,
+        Priority::High => 1.0,
+    }
+}
+
+/// A `429 Too Many Requests` response with a Kubernetes `Status` body, as the API server would return it
+fn too_many_requests(retry_after_seconds: u64) -> Response<Body> {
+    let status = serde_json::json!({
+        "kind": "Status",
+        "apiVersion": "v1",
+        "metadata": {},
+        "status": "Failure",
+        "message": "too many requests of the module are waiting for its rate limit",
+        "reason": "TooManyRequests",
+        "details": { "retryAfterSeconds": retry_after_seconds },
+        "code": 429,
+    });
+
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::RETRY_AFTER, retry_after_seconds)
+        .body(Body::from(status.to_string()))
+        .expect("a valid response")
+}
+
+/// The global rate limit of the host, split in shares per priority, and the rate limits of modules
+pub(crate) struct ClientLimits {
+    global: Option<Gate>,
+    /// Lower priorities are limited to a share of the global rate limit on top of it
+    priorities: HashMap<Priority, Gate>,
+    module_rate_limit: Option<RateLimitConfig>,
+    module_queue_size: usize,
+}
+
+impl ClientLimits {
+    pub(crate) fn new(config: &ClientConfig) -> Self {
+        let priorities = match &config.rate_limit {
+            None => HashMap::new(),
+            Some(rate_limit) => [Priority::Low, Priority::Normal]
+                .iter()
+                .map(|priority| {
+                    let rate_limit = rate_limit.share(share(*priority));
+                    (*priority, gate(&rate_limit, SHARED_QUEUE_SIZE))
+                })
+                .collect(),
+        };
+
+        Self {
+            global: config
+                .rate_limit
+                .as_ref()
+                .map(|rate_limit| gate(rate_limit, SHARED_QUEUE_SIZE)),
+            priorities,
+            module_rate_limit: config.module_rate_limit.clone(),
+            module_queue_size: config.module_queue_size,
+        }
+    }
+
+    /// The rate limit of a module, `rate_limit` overrides the default of the host config
+    pub(crate) fn module(
+        &self,
+        service: KubeClientService,
+        rate_limit: Option<&RateLimitConfig>,
+        priority: Priority,
+    ) -> anyhow::Result<KubeClientService> {
+        let module = match rate_limit.or(self.module_rate_limit.as_ref()) {
+            Some(rate_limit) => {
+                rate_limit.validate().context("invalid rate limit")?;
+                let retry_after_seconds = (1.0 / rate_limit.qps).ceil().max(1.0) as u64;
+                Some((
+                    gate(rate_limit, self.module_queue_size),
+                    retry_after_seconds,
+                ))
+            }
+            None => None,
+        };
+        let gates = self
+            .priorities
+            .get(&priority)
+            .into_iter()
+            .chain(&self.global)
+            .cloned()
+            .collect();
+
+        Ok(limit(service, module, gates))
+    }
+}
+
+/// Take a token of every gate before sending a request, a module with a full queue gets a 429
+fn limit(
+    service: KubeClientService,
+    module: Option<(Gate, u64)>,
+    gates: Vec<Gate>,
+) -> KubeClientService {
+    if module.is_none() && gates.is_empty() {
+        return service;
+    }
+
+    let limited = tower::service_fn(move |request: Request<Body>| {
+        send_limited(service.clone(), module.clone(), gates.clone(), request)
+    });
+
+    Buffer::new(BoxService::new(limited), SHARED_QUEUE_SIZE)
+}
+
+/// Wait for the limits of a request and send it. The waits are boxed, because the compiler can't
+/// prove that the future of a boxed-error service is `Send` for every lifetime otherwise.
+async fn send_limited(
+    service: KubeClientService,
+    module: Option<(Gate, u64)>,
+    gates: Vec<Gate>,
+    request: Request<Body>,
+) -> Result<Response<Body>, BoxError> {
+    if let Some((module, retry_after_seconds)) = module {
+        match LoadShed::new(module).oneshot(()).boxed().await {
+            Ok(()) => {}
+            Err(err) if err.is::<Overloaded>() => {
+                warn!(
+                    "throttled {} {}: the module has too many requests waiting",
+                    request.method(),
+                    request.uri()
+                );
+                return Ok(too_many_requests(retry_after_seconds));
+            }
+            Err(err) => return Err(err),
+        }
+    }
+
+    for gate in gates {
+        gate.oneshot(()).boxed().await?;
+    }
+
+    service.oneshot(request).boxed().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_token_bucket() {
+        let mut bucket = TokenBucket::new(&RateLimitConfig { qps: 2.0, burst: 2 });
+        let take = |bucket: &mut TokenBucket| match bucket.ready().now_or_never() {
+            Some(ready) => ready.unwrap().call(()).now_or_never().is_some(),
+            None => false,
+        };
+
+        // the burst is available at once
+        assert!(take(&mut bucket) && take(&mut bucket));
+        assert!(!take(&mut bucket));
+
+        // a token is added every half second, not the whole burst at once
+        bucket.refilled -= Duration::from_millis(500);
+        assert!(take(&mut bucket));
+        assert!(!take(&mut bucket));
+
+        // tokens are saved up to the burst
+        bucket.refilled -= Duration::from_secs(10);
+        assert!(take(&mut bucket) && take(&mut bucket));
+        assert!(!take(&mut bucket));
+    }
+
+    #[tokio::test]
+    async fn test_module_queue_full() {
+        let service = Buffer::new(
+            BoxService::new(tower::service_fn(|_: Request<Body>| async {
+                Ok::<_, BoxError>(Response::new(Body::empty()))
+            })),
+            10,
+        );
+        let limits = ClientLimits::new(&ClientConfig {
+            rate_limit: None,
+            module_rate_limit: Some(RateLimitConfig { qps: 0.1, burst: 1 }),
+            module_queue_size: 1,
+            ..Default::default()
+        });
+        let service = limits.module(service, None, Priority::Normal).unwrap();
+
+        let request = || Request::get("/api/v1/pods").body(Body::empty()).unwrap();
+        // the burst passes, the next request waits for the rate limit and the one after that is throttled
+        let first = service.clone().oneshot(request()).await.unwrap();
+        assert_eq!(first.status(), StatusCode::OK);
+
+        let waiting = tokio::spawn(service.clone().oneshot(request()));
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+
+        let throttled = service.clone().oneshot(request()).await.unwrap();
+        assert_eq!(throttled.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(throttled.headers()[header::RETRY_AFTER], "10");
+        waiting.abort();
+    }
+}
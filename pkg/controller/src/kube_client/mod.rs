@@ -1,3 +1,4 @@
+use crate::config::ClientConfig;
 use anyhow::Error;
 use futures::stream::Stream;
 use http::header::HeaderMap;
@@ -6,6 +7,7 @@ use http_body::Body as HttpBody;
 use hyper::Body;
 use hyper_timeout::TimeoutConnector;
 use kube::client::ConfigExt;
+use kube::config::AuthInfo;
 use kube::Config;
 use pin_project::pin_project;
 use std::time::Duration;
@@ -13,6 +15,7 @@ use std::{
     pin::Pin,
     task::{Context, Poll},
 };
+use tower::retry::RetryLayer;
 use tower::{buffer::Buffer, util::BoxService, BoxError};
 use tower::{Layer, ServiceBuilder, ServiceExt};
 use tower_http::{
@@ -20,6 +23,18 @@ use tower_http::{
 };
 use tracing::Span;
 
+mod auth;
+mod limit;
+mod recording;
+mod retry;
+
+use auth::{RefreshAuthLayer, RefreshingToken};
+pub(crate) use limit::ClientLimits;
+pub(crate) use recording::{record, replay};
+pub use recording::{ClientMode, Recorder, Recording};
+pub use retry::RetryBody;
+use retry::RetryPolicy;
+
 // Wrap `http_body::Body` to implement `Stream`.
 #[pin_project]
 pub struct IntoStream<B> {
@@ -58,25 +73,56 @@ impl<T> BodyStreamExt for T where T: HttpBody {}
 pub type KubeClientService =
     Buffer<BoxService<Request<Body>, Response<Body>, BoxError>, Request<Body>>;
 
-pub(crate) async fn create_client_service(kubeconfig: Config) -> Result<KubeClientService, Error> {
-    //let timeout = Some(Duration::new(9999999999999, 0));
+/// Kubeconfig of a module that authenticates with its own bearer token file
+pub(crate) fn with_token_file(kubeconfig: &Config, token_file: &std::path::Path) -> Config {
+    let mut kubeconfig = kubeconfig.clone();
+    kubeconfig.auth_info = AuthInfo {
+        token_file: Some(token_file.display().to_string()),
+        ..Default::default()
+    };
+    kubeconfig
+}
 
-    let client: hyper::Client<_, Body> = {
-        let connector = kubeconfig.rustls_https_connector()?;
+pub(crate) async fn create_client_service(
+    kubeconfig: Config,
+    client_config: &ClientConfig,
+) -> Result<KubeClientService, Error> {
+    let millis = |ms: Option<u64>| ms.map(Duration::from_millis);
+
+    // timeouts are delivered to the module as transport errors of its request
+    let build_client = |read_timeout: Option<u64>| -> Result<hyper::Client<_, Body>, Error> {
+        let mut connector = TimeoutConnector::new(kubeconfig.rustls_https_connector()?);
+        connector.set_connect_timeout(millis(client_config.connect_timeout_ms));
+        connector.set_read_timeout(millis(read_timeout));
+        connector.set_write_timeout(millis(client_config.write_timeout_ms));
+
+        Ok(hyper::Client::builder().build(connector))
+    };
 
-        let connector = TimeoutConnector::new(connector);
-        // error handling is not really well implemented, if a connection times out we crash...
-        //connector.set_connect_timeout(timeout);
-        //connector.set_read_timeout(timeout);
-        //connector.set_write_timeout(timeout);
+    // watches are idle until something changes, so they use their own connections with a longer read timeout
+    let client = build_client(client_config.read_timeout_ms)?;
+    let watch_client = build_client(client_config.watch_read_timeout_ms)?;
+    let client = tower::service_fn(move |request: Request<Body>| {
+        let client = match retry::is_long_lived(request.method(), request.uri()) {
+            true => watch_client.clone(),
+            false => client.clone(),
+        };
+        client.request(request)
+    });
 
-        hyper::Client::builder().build(connector)
+    // token files and credential plugins are read again when their token is rotated or expires
+    let refresh_auth_layer = RefreshingToken::new(&kubeconfig.auth_info).map(RefreshAuthLayer::new);
+    let auth_layer = match refresh_auth_layer {
+        Some(_) => None,
+        None => kubeconfig.auth_layer()?,
     };
 
     let service = ServiceBuilder::new()
+        .layer(RetryLayer::new(RetryPolicy::new(client_config)))
         .layer(kubeconfig.base_uri_layer())
         .layer(tower_http::decompression::DecompressionLayer::new())
-        .option_layer(kubeconfig.auth_layer()?)
+        .option_layer(auth_layer)
+        .option_layer(refresh_auth_layer)
         .layer(kubeconfig.extra_headers_layer()?)
         .layer(
             // Attribute names follow [Semantic Conventions].
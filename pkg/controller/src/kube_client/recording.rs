@@ -0,0 +1,357 @@
+use super::{BodyStreamExt, KubeClientService, RetryBody};
+use anyhow::{Context, Result};
+use bytes::Bytes;
+use futures::stream::{self, BoxStream};
+use futures::StreamExt;
+use http::{HeaderMap, Method, Request, Response, StatusCode};
+use hyper::Body;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use tower::{buffer::Buffer, util::BoxService, BoxError, ServiceExt};
+use tracing::{error, warn};
+
+/// Text when the data is valid UTF-8, which keeps recordings readable
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+enum Data {
+    Text(String),
+    Binary(Vec<u8>),
+}
+
+impl From<&Bytes> for Data {
+    fn from(data: &Bytes) -> Self {
+        match std::str::from_utf8(data) {
+            Ok(text) => Data::Text(text.to_string()),
+            Err(_) => Data::Binary(data.to_vec()),
+        }
+    }
+}
+
+impl From<Data> for Bytes {
+    fn from(data: Data) -> Self {
+        match data {
+            Data::Text(text) => Bytes::from(text),
+            Data::Binary(data) => Bytes::from(data),
+        }
+    }
+}
+
+/// A line of a recording, the entries of concurrent requests are interleaved
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Entry {
+    Request {
+        id: u64,
+        cluster: String,
+        #[serde(with = "http_serde::method")]
+        method: Method,
+        /// Path and query, the url of the cluster is left out
+        path: String,
+        body: Data,
+    },
+    Response {
+        id: u64,
+        #[serde(with = "http_serde::status_code")]
+        status: StatusCode,
+        #[serde(with = "http_serde::header_map")]
+        headers: HeaderMap,
+    },
+    Chunk {
+        id: u64,
+        data: Data,
+    },
+    End {
+        id: u64,
+    },
+    Error {
+        id: u64,
+        message: String,
+    },
+}
+
+/// How the Kubernetes API requests of the host are served
+pub enum ClientMode {
+    Live,
+    /// Write every request and response to a recording
+    Record(Arc<Recorder>),
+    /// Answer requests with the responses of a recording, without a cluster
+    Replay(Arc<Recording>),
+}
+
+/// Writes the requests and responses of the clients to a file, one JSON entry per line
+pub struct Recorder {
+    next_id: AtomicU64,
+    file: Mutex<LineWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create recording {}", path.display()))?;
+
+        Ok(Self {
+            next_id: AtomicU64::new(0),
+            file: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    fn write(&self, entry: &Entry) {
+        let mut line = serde_json::to_vec(entry).expect("serializing a recording entry");
+        line.push(b'\n');
+
+        if let Err(err) = self.file.lock().unwrap().write_all(&line) {
+            error!("failed to write the recording: {}", err);
+        }
+    }
+}
+
+/// Record the exchanges of a client service, including the chunks of streamed responses as they arrive
+pub(crate) fn record(
+    service: KubeClientService,
+    recorder: Arc<Recorder>,
+    cluster: &str,
+) -> KubeClientService {
+    let cluster = cluster.to_string();
+    let recording = tower::service_fn(move |request: Request<Body>| {
+        let service = service.clone();
+        let recorder = recorder.clone();
+        let id = recorder.next_id.fetch_add(1, Ordering::SeqCst);
+
+        recorder.write(&Entry::Request {
+            id,
+            cluster: cluster.clone(),
+            method: request.method().clone(),
+            path: request
+                .uri()
+                .path_and_query()
+                .map_or_else(String::new, |path| path.to_string()),
+            // request executors keep a copy of every body
+            body: request
+                .extensions()
+                .get::<RetryBody>()
+                .map_or(Data::Text(String::new()), |body| Data::from(&body.0)),
+        });
+
+        async move {
+            let response = match service.oneshot(request).await {
+                Ok(response) => response,
+                Err(err) => {
+                    recorder.write(&Entry::Error {
+                        id,
+                        message: err.to_string(),
+                    });
+                    return Err(err);
+                }
+            };
+            recorder.write(&Entry::Response {
+                id,
+                status: response.status(),
+                headers: response.headers().clone(),
+            });
+
+            let (parts, body) = response.into_parts();
+            let chunk_recorder = recorder.clone();
+            let chunks = body.into_stream().inspect(move |chunk| {
+                chunk_recorder.write(&match chunk {
+                    Ok(data) => Entry::Chunk {
+                        id,
+                        data: Data::from(data),
+                    },
+                    Err(err) => Entry::Error {
+                        id,
+                        message: err.to_string(),
+                    },
+                })
+            });
+            let end = stream::once(async move {
+                recorder.write(&Entry::End { id });
+                None::<Result<Bytes, hyper::Error>>
+            })
+            .filter_map(futures::future::ready);
+
+            Ok(Response::from_parts(
+                parts,
+                Body::wrap_stream(chunks.chain(end)),
+            ))
+        }
+    });
+
+    Buffer::new(BoxService::new(recording), 1024)
+}
+
+/// A recorded exchange, as it is replayed
+#[derive(Default)]
+struct Exchange {
+    response: Option<(StatusCode, HeaderMap)>,
+    chunks: Vec<Bytes>,
+    /// `None` if the response was still streaming when the recording stopped
+    end: Option<Result<(), String>>,
+}
+
+type ExchangeKey = (String, Method, String, Bytes);
+
+/// The exchanges of a recording, the requests with the same cluster, method, path and body are answered in the recorded order
+pub struct Recording {
+    exchanges: Mutex<HashMap<ExchangeKey, VecDeque<Exchange>>>,
+}
+
+impl Recording {
+    pub fn load(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("failed to open recording {}", path.display()))?;
+
+        let mut keys = HashMap::new();
+        let mut exchanges = BTreeMap::<u64, Exchange>::new();
+        for (nr, line) in BufReader::new(file).lines().enumerate() {
+            let entry = serde_json::from_str::<Entry>(&line?)
+                .with_context(|| format!("invalid entry on line {} of the recording", nr + 1))?;
+
+            match entry {
+                Entry::Request {
+                    id,
+                    cluster,
+                    method,
+                    path,
+                    body,
+                } => {
+                    keys.insert(id, (cluster, method, path, Bytes::from(body)));
+                    exchanges.insert(id, Exchange::default());
+                }
+                Entry::Response {
+                    id,
+                    status,
+                    headers,
+                } => exchanges.entry(id).or_default().response = Some((status, headers)),
+                Entry::Chunk { id, data } => {
+                    exchanges.entry(id).or_default().chunks.push(data.into())
+                }
+                Entry::End { id } => {
+                    // a stream that failed has ended already
+                    exchanges.entry(id).or_default().end.get_or_insert(Ok(()));
+                }
+                Entry::Error { id, message } => {
+                    exchanges.entry(id).or_default().end = Some(Err(message))
+                }
+            }
+        }
+
+        let mut by_key = HashMap::<ExchangeKey, VecDeque<Exchange>>::new();
+        for (id, exchange) in exchanges {
+            match keys.remove(&id) {
+                Some(key) => by_key.entry(key).or_default().push_back(exchange),
+                None => warn!("the recording has no request for exchange {}", id),
+            }
+        }
+
+        Ok(Self {
+            exchanges: Mutex::new(by_key),
+        })
+    }
+
+    fn respond(&self, cluster: &str, request: &Request<Body>) -> Result<Response<Body>, BoxError> {
+        let path = request
+            .uri()
+            .path_and_query()
+            .map_or_else(String::new, |path| path.to_string());
+        let body = request
+            .extensions()
+            .get::<RetryBody>()
+            .map_or_else(Bytes::new, |body| body.0.clone());
+        let key = (cluster.to_string(), request.method().clone(), path, body);
+
+        let exchange = self
+            .exchanges
+            .lock()
+            .unwrap()
+            .get_mut(&key)
+            .and_then(VecDeque::pop_front)
+            .ok_or_else(|| format!("no recorded response for {} {}", key.1, key.2))?;
+
+        let (status, headers) = match exchange.response {
+            Some(response) => response,
+            None => {
+                return Err(match exchange.end {
+                    Some(Err(message)) => message,
+                    _ => format!("no recorded response for {} {}", key.1, key.2),
+                }
+                .into())
+            }
+        };
+
+        let end: BoxStream<'static, Result<Bytes, BoxError>> = match exchange.end {
+            Some(Ok(())) => stream::empty().boxed(),
+            Some(Err(message)) => stream::once(async move { Err(BoxError::from(message)) }).boxed(),
+            // the stream stays open like it was when the recording stopped
+            None => stream::pending().boxed(),
+        };
+        let chunks = stream::iter(exchange.chunks.into_iter().map(Ok)).chain(end);
+
+        let mut response = Response::new(Body::wrap_stream(chunks));
+        *response.status_mut() = status;
+        *response.headers_mut() = headers;
+        Ok(response)
+    }
+}
+
+/// A client service that answers requests with the responses of a recording
+pub(crate) fn replay(recording: Arc<Recording>, cluster: &str) -> KubeClientService {
+    let cluster = cluster.to_string();
+    let replaying = tower::service_fn(move |request: Request<Body>| {
+        futures::future::ready(recording.respond(&cluster, &request))
+    });
+
+    Buffer::new(BoxService::new(replaying), 1024)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_record_and_replay() {
+        let path = std::env::temp_dir().join(format!("recording-{}.jsonl", std::process::id()));
+
+        let live = Buffer::new(
+            BoxService::new(tower::service_fn(|request: Request<Body>| async move {
+                let body = match request.uri().path() {
+                    "/api/v1/pods" => Body::wrap_stream(stream::iter(vec![
+                        Ok::<_, BoxError>(Bytes::from("{\"type\":\"ADDED\"}\n")),
+                        Ok(Bytes::from_static(&[0xff, 0xfe])),
+                    ])),
+                    _ => Body::empty(),
+                };
+                Ok::<_, BoxError>(Response::new(body))
+            })),
+            10,
+        );
+        let recorder = Arc::new(Recorder::create(&path).unwrap());
+        let recorded = record(live, recorder, "default");
+
+        let request = || {
+            let mut request = Request::get("/api/v1/pods?watch=true")
+                .body(Body::empty())
+                .unwrap();
+            request.extensions_mut().insert(RetryBody(Bytes::new()));
+            request
+        };
+        let response = recorded.oneshot(request()).await.unwrap();
+        let live_body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        let replayed = replay(Arc::new(Recording::load(&path).unwrap()), "default");
+        let response = replayed.clone().oneshot(request()).await.unwrap();
+        assert_eq!(
+            hyper::body::to_bytes(response.into_body()).await.unwrap(),
+            live_body
+        );
+
+        // every recorded exchange is replayed once
+        assert!(replayed.oneshot(request()).await.is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
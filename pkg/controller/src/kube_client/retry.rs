@@ -0,0 +1,245 @@
+use crate::config::ClientConfig;
+use crate::runtime::http_engine::policy;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use http::{Method, Request, Response, StatusCode};
+use hyper::Body;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::{Duration, SystemTime};
+use tower::retry::Policy;
+use tracing::warn;
+
+const RETRY_STATUS_CODES: [StatusCode; 3] = [
+    StatusCode::BAD_GATEWAY,
+    StatusCode::SERVICE_UNAVAILABLE,
+    StatusCode::GATEWAY_TIMEOUT,
+];
+
+/// Copy of the body of a request, a `hyper::Body` can only be sent once
+#[derive(Debug, Clone)]
+pub struct RetryBody(pub bytes::Bytes);
+
+/// Watches and followed logs are long-lived, instead of retrying them the module restarts them
+pub(crate) fn is_long_lived(method: &Method, uri: &http::Uri) -> bool {
+    policy::is_watch(method, uri) || policy::query_flag(uri, "follow")
+}
+
+/// The `Retry-After` header in seconds, the API server does not use the date format
+fn retry_after<B>(response: &Response<B>) -> Option<Duration> {
+    response
+        .headers()
+        .get(http::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+fn is_idempotent(method: &Method) -> bool {
+    matches!(
+        *method,
+        Method::GET | Method::HEAD | Method::OPTIONS | Method::PUT | Method::DELETE
+    )
+}
+
+/// A random number in `[0, 1)`, the hasher is seeded differently every time
+fn random() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    (hasher.finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// Retry idempotent requests after a connection error or a temporary server error,
+/// with an exponential backoff and jitter
+#[derive(Debug, Clone)]
+pub(crate) struct RetryPolicy {
+    attempt: u32,
+    max_retries: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub(crate) fn new(config: &ClientConfig) -> Self {
+        Self {
+            attempt: 0,
+            max_retries: config.max_retries,
+            base_delay: Duration::from_millis(config.retry_base_delay_ms),
+            max_delay: Duration::from_millis(config.retry_max_delay_ms),
+        }
+    }
+
+    fn backoff(&self) -> Duration {
+        let delay = self
+            .base_delay
+            .checked_mul(1 << self.attempt.min(16))
+            .map_or(self.max_delay, |delay| delay.min(self.max_delay));
+
+        // spread the retries of requests that failed at the same time
+        delay.mul_f64(0.5 + random() / 2.0)
+    }
+}
+
+impl<B, E> Policy<Request<Body>, Response<B>, E> for RetryPolicy
+where
+    E: std::fmt::Display,
+{
+    type Future = BoxFuture<'static, Self>;
+
+    fn retry(&self, req: &Request<Body>, result: Result<&Response<B>, &E>) -> Option<Self::Future> {
+        if self.attempt >= self.max_retries {
+            return None;
+        }
+
+        let (reason, delay) = match result {
+            Ok(response) if RETRY_STATUS_CODES.contains(&response.status()) => {
+                (response.status().to_string(), self.backoff())
+            }
+            // throttled by API priority and fairness, the module gets the 429 if it has to wait too long
+            Ok(response) if response.status() == StatusCode::TOO_MANY_REQUESTS => {
+                match retry_after(response) {
+                    Some(delay) if delay <= self.max_delay => {
+                        (response.status().to_string(), delay.max(self.backoff()))
+                    }
+                    _ => return None,
+                }
+            }
+            Ok(_) => return None,
+            Err(err) => (err.to_string(), self.backoff()),
+        };
+
+        warn!(
+            "retrying {} {} in {:?} after {} (retry {} of {})",
+            req.method(),
+            req.uri(),
+            delay,
+            reason,
+            self.attempt + 1,
+            self.max_retries
+        );
+
+        let next = Self {
+            attempt: self.attempt + 1,
+            ..self.clone()
+        };
+        Some(
+            async move {
+                tokio::time::sleep(delay).await;
+                next
+            }
+            .boxed(),
+        )
+    }
+
+    fn clone_request(&self, req: &Request<Body>) -> Option<Request<Body>> {
+        if self.max_retries == 0
+            || !is_idempotent(req.method())
+            || is_long_lived(req.method(), req.uri())
+        {
+            return None;
+        }
+
+        clone_request(req)
+    }
+}
+
+/// Copy a request that carries a `RetryBody`, so it can be sent again
+pub(crate) fn clone_request(req: &Request<Body>) -> Option<Request<Body>> {
+    let body = req.extensions().get::<RetryBody>()?.clone();
+
+    let mut clone = Request::new(Body::from(body.0.clone()));
+    *clone.method_mut() = req.method().clone();
+    *clone.uri_mut() = req.uri().clone();
+    *clone.version_mut() = req.version();
+    *clone.headers_mut() = req.headers().clone();
+    clone.extensions_mut().insert(body);
+
+    Some(clone)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backoff() {
+        let mut policy = RetryPolicy::new(&ClientConfig {
+            retry_base_delay_ms: 100,
+            retry_max_delay_ms: 1000,
+            ..Default::default()
+        });
+
+        for (attempt, max) in [(0, 100), (2, 400), (5, 1000), (40, 1000)] {
+            policy.attempt = attempt;
+            let delay = policy.backoff();
+            assert!(delay >= Duration::from_millis(max / 2) && delay <= Duration::from_millis(max));
+        }
+    }
+
+    #[test]
+    fn test_is_long_lived() {
+        let long_lived = |uri: &str| is_long_lived(&Method::GET, &uri.parse().unwrap());
+
+        assert!(long_lived("/api/v1/pods?watch=True"));
+        assert!(long_lived("/api/v1/watch/namespaces/default/pods"));
+        assert!(long_lived("/api/v1/namespaces/default/pods/a/log?follow=t"));
+        assert!(!long_lived("/api/v1/pods?watch=false"));
+        assert!(!long_lived(
+            "/api/v1/namespaces/default/pods/a/log?followers=true"
+        ));
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let policy = RetryPolicy::new(&ClientConfig::default());
+        let request = Request::get("/api/v1/pods").body(Body::empty()).unwrap();
+        let throttled = |retry_after: &str| {
+            Response::builder()
+                .status(StatusCode::TOO_MANY_REQUESTS)
+                .header(http::header::RETRY_AFTER, retry_after)
+                .body(())
+                .unwrap()
+        };
+
+        let retry = |response| {
+            Policy::<_, _, hyper::Error>::retry(&policy, &request, Ok(&response)).is_some()
+        };
+        assert!(retry(throttled("1")));
+        // waiting longer than the maximum delay is left to the module
+        assert!(!retry(throttled("60")));
+    }
+
+    #[test]
+    fn test_clone_request() {
+        let policy = RetryPolicy::new(&ClientConfig::default());
+
+        let mut request = Request::put("/api/v1/namespaces/default/configmaps/a")
+            .body(Body::from("{}"))
+            .unwrap();
+        // without a copy of the body the request can't be retried
+        assert!(
+            Policy::<_, Response<Body>, hyper::Error>::clone_request(&policy, &request).is_none()
+        );
+
+        request
+            .extensions_mut()
+            .insert(RetryBody(bytes::Bytes::from("{}")));
+        assert!(
+            Policy::<_, Response<Body>, hyper::Error>::clone_request(&policy, &request).is_some()
+        );
+
+        let watch = Request::get("/api/v1/pods?watch=true")
+            .body(Body::empty())
+            .unwrap();
+        assert!(
+            Policy::<_, Response<Body>, hyper::Error>::clone_request(&policy, &watch).is_none()
+        );
+    }
+}
@@ -0,0 +1,225 @@
+use crate::config::LoggingConfig;
+use serde::Deserialize;
+use std::io::Write;
+use std::str::FromStr;
+use tracing_subscriber::EnvFilter;
+use wasi_common::pipe::WritePipe;
+
+// guests that never print a newline should not make us buffer forever
+const MAX_LINE_LENGTH: usize = 16 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            other => Err(anyhow::anyhow!("unknown log format '{}'", other)),
+        }
+    }
+}
+
+/// Install the global tracing subscriber
+pub fn init(config: &LoggingConfig) -> anyhow::Result<()> {
+    let filter = EnvFilter::try_new(config.directives())
+        .map_err(|e| anyhow::anyhow!("Invalid log filter '{}': {}", config.directives(), e))?;
+
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+
+    match config.format {
+        LogFormat::Text => builder.init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .init(),
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum GuestStream {
+    Stdout,
+    Stderr,
+}
+
+/// Line buffered writer that turns the stdout/stderr of a guest into tracing events
+/// tagged with the name of the module that produced them.
+pub struct GuestOutput {
+    module: String,
+    stream: GuestStream,
+    buffer: Vec<u8>,
+}
+
+impl GuestOutput {
+    pub fn new(module: String, stream: GuestStream) -> Self {
+        Self {
+            module,
+            stream,
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Wrap the writer in a WASI pipe, so it can be used as stdout/stderr of a `WasiCtx`
+    pub fn into_pipe(self) -> Box<WritePipe<GuestOutput>> {
+        Box::new(WritePipe::new(self))
+    }
+
+    fn emit(&self, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end_matches('\r');
+
+        match self.stream {
+            GuestStream::Stdout => {
+                tracing::info!(target: "guest", module = %self.module, stream = "stdout", "{}", line)
+            }
+            GuestStream::Stderr => {
+                tracing::warn!(target: "guest", module = %self.module, stream = "stderr", "{}", line)
+            }
+        }
+    }
+}
+
+impl Write for GuestOutput {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+
+        while let Some(pos) = self.buffer.iter().position(|b| *b == b'\n') {
+            let line: Vec<u8> = self.buffer.drain(..=pos).collect();
+            self.emit(&line[..pos]);
+        }
+
+        if self.buffer.len() > MAX_LINE_LENGTH {
+            let line = std::mem::take(&mut self.buffer);
+            self.emit(&line);
+        }
+
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        // partial lines are kept until the newline arrives
+        Ok(())
+    }
+}
+
+impl Drop for GuestOutput {
+    fn drop(&mut self) {
+        if !self.buffer.is_empty() {
+            let line = std::mem::take(&mut self.buffer);
+            self.emit(&line);
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) enum GuestLogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Data structure to deserialize a log event forwarded by the guest over the `log-abi`
+#[derive(Deserialize)]
+pub(crate) struct GuestLogRecord {
+    level: GuestLogLevel,
+    target: String,
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl GuestLogRecord {
+    /// Emit the guest event in the host tracing pipeline, inside the currently entered (module) span.
+    ///
+    /// Tracing needs the target and the field names of an event at compile time, so every guest event
+    /// has the `guest` target, with the target of the guest in `guest_target` and its fields joined
+    /// into one `fields` value. Log filters can only select guest events as a whole, by `guest`.
+    pub(crate) fn emit(&self) {
+        let fields = self
+            .fields
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect::<Vec<String>>()
+            .join(" ");
+
+        match self.level {
+            GuestLogLevel::Trace => {
+                tracing::trace!(target: "guest", guest_target = %self.target, fields = %fields, "{}", self.message)
+            }
+            GuestLogLevel::Debug => {
+                tracing::debug!(target: "guest", guest_target = %self.target, fields = %fields, "{}", self.message)
+            }
+            GuestLogLevel::Info => {
+                tracing::info!(target: "guest", guest_target = %self.target, fields = %fields, "{}", self.message)
+            }
+            GuestLogLevel::Warn => {
+                tracing::warn!(target: "guest", guest_target = %self.target, fields = %fields, "{}", self.message)
+            }
+            GuestLogLevel::Error => {
+                tracing::error!(target: "guest", guest_target = %self.target, fields = %fields, "{}", self.message)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Clone, Default)]
+    struct Output(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Output {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_guest_log_record_json() {
+        let output = Output::default();
+        let writer = output.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .json()
+            .with_writer(move || writer.clone())
+            .finish();
+
+        let record = GuestLogRecord {
+            level: GuestLogLevel::Info,
+            target: "simple_controller::reconcile".to_string(),
+            message: "reconciled".to_string(),
+            fields: vec![
+                ("name".to_string(), "web".to_string()),
+                ("generation".to_string(), "2".to_string()),
+            ],
+        };
+        tracing::subscriber::with_default(subscriber, || record.emit());
+
+        let event: serde_json::Value = serde_json::from_slice(&output.0.lock().unwrap()).unwrap();
+        assert_eq!(event["level"], "INFO");
+        assert_eq!(event["target"], "guest");
+        assert_eq!(event["fields"]["message"], "reconciled");
+        assert_eq!(
+            event["fields"]["guest_target"],
+            "simple_controller::reconcile"
+        );
+        assert_eq!(event["fields"]["fields"], "name=web generation=2");
+    }
+}
@@ -1,14 +1,18 @@
-use kube::Config;
-use std::env;
-use std::path::PathBuf;
-use tracing::info;
+use clap::Parser;
+use tracing::error;
 
 mod abi;
+mod cli;
+mod commands;
+mod config;
 mod kube_client;
+mod logging;
+mod metrics;
 mod modules;
 mod runtime;
 
-use crate::modules::ControllerModuleMetadata;
+use crate::cli::{Cli, Command};
+use crate::config::HostConfig;
 
 use std::alloc::System;
 
@@ -16,69 +20,23 @@ use std::alloc::System;
 static A: System = System;
 
 fn main() {
-    std::env::set_var(
-        "RUST_LOG",
-        //"debug"
-        "debug,tower=warn,rustls=warn,wasmtime_cranelift=warn,cranelift=warn,regalloc=warn,hyper=warn",
-    );
+    let cli = Cli::parse();
 
-    tracing_subscriber::fmt::init();
+    let mut host_config =
+        HostConfig::load(cli.config.as_deref()).expect("Cannot load the host config");
 
-    // Bootstrap tokio runtime and kube-rs-async config/client
-    let runtime = tokio::runtime::Builder::new_multi_thread()
-        .enable_all()
-        .build()
-        .expect("Cannot create a tokio runtime");
+    cli.logging.apply(&mut host_config.logging);
+    logging::init(&host_config.logging).expect("Cannot setup logging");
 
-    let kubeconfig = runtime
-        .block_on(Config::infer())
-        .expect("Cannot infer the kubeconfig");
-
-    let cluster_url = kubeconfig.cluster_url.clone();
-
-    let service = runtime
-        .block_on(kube_client::create_client_service(kubeconfig))
-        .expect("could not setup kube client");
+    let result = cli.into_command().and_then(|command| match command {
+        Command::Run(args) => commands::run(&host_config, args),
+        Command::Precompile(args) => commands::precompile(args),
+        Command::ValidateConfig(args) => commands::validate_config(&host_config, args),
+        Command::InspectSnapshot(args) => commands::inspect_snapshot(args),
+    });
 
-    let mut args: Vec<String> = env::args().collect();
-    if args.len() < 2 {
-        panic!("Usage: {} <modules-dir>", args.remove(0))
+    if let Err(err) = result {
+        error!("{:?}", err);
+        std::process::exit(1);
     }
-
-    let path = PathBuf::from(args.remove(1));
-    info!("Going to load from {}", path.to_str().unwrap());
-
-    let cache_path = std::env::temp_dir().join("cache");
-    std::fs::create_dir_all(&cache_path).unwrap();
-
-    let swap_path = std::env::temp_dir().join("swap");
-    std::fs::create_dir_all(&swap_path).unwrap();
-
-    let mods = ControllerModuleMetadata::load_modules_from_dir(path)
-        .expect("Cannot load the modules from the provided dir");
-
-    runtime.block_on(async {
-        let (runtime_command_sender, runtime_command_receiver) = tokio::sync::mpsc::channel(10);
-
-        tokio::spawn(runtime::start(
-            runtime_command_receiver,
-            cluster_url,
-            service,
-            cache_path,
-            swap_path,
-        ));
-
-        tokio::spawn(async move {
-            for module_metadata in mods {
-                runtime_command_sender
-                    .send(runtime::Command::StartModule(module_metadata))
-                    .await
-                    .map_err(|e| anyhow::anyhow!("{}", e))
-                    .unwrap();
-            }
-        });
-
-        tokio::signal::ctrl_c().await.unwrap();
-        info!("Closing")
-    });
 }
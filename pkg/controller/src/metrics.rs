@@ -0,0 +1,252 @@
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::error::{SendError, TrySendError};
+use tokio::sync::mpsc::{Receiver, Sender};
+use tracing::{info, warn};
+
+// a producer blocked for longer than this points to a guest that can't keep up
+const SLOW_SEND_WARNING: Duration = Duration::from_secs(1);
+
+/// Depth and backpressure counters of a bounded channel
+#[derive(Debug)]
+pub struct QueueMetrics {
+    capacity: usize,
+    depth: AtomicI64,
+    sent: AtomicU64,
+    blocked: AtomicU64,
+    blocked_micros: AtomicU64,
+}
+
+impl QueueMetrics {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            depth: AtomicI64::new(0),
+            sent: AtomicU64::new(0),
+            blocked: AtomicU64::new(0),
+            blocked_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn record_send(&self, blocked_for: Option<Duration>) {
+        self.depth.fetch_add(1, Ordering::Relaxed);
+        self.sent.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(blocked_for) = blocked_for {
+            self.blocked.fetch_add(1, Ordering::Relaxed);
+            self.blocked_micros
+                .fetch_add(blocked_for.as_micros() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn record_receive(&self) {
+        self.depth.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Create a bounded channel of which the depth and the time producers spend blocked are recorded
+pub fn metered_channel<T>(
+    name: &str,
+    capacity: usize,
+    metrics: Arc<QueueMetrics>,
+) -> (MeteredSender<T>, MeteredReceiver<T>) {
+    let (sender, receiver) = tokio::sync::mpsc::channel(capacity);
+
+    (
+        MeteredSender {
+            name: name.to_string(),
+            sender,
+            metrics: metrics.clone(),
+        },
+        MeteredReceiver { receiver, metrics },
+    )
+}
+
+#[derive(Debug)]
+pub struct MeteredSender<T> {
+    name: String,
+    sender: Sender<T>,
+    metrics: Arc<QueueMetrics>,
+}
+
+// derive would require `T: Clone`
+impl<T> Clone for MeteredSender<T> {
+    fn clone(&self) -> Self {
+        Self {
+            name: self.name.clone(),
+            sender: self.sender.clone(),
+            metrics: self.metrics.clone(),
+        }
+    }
+}
+
+impl<T> MeteredSender<T> {
+    pub async fn send(&self, value: T) -> Result<(), SendError<T>> {
+        let value = match self.sender.try_send(value) {
+            Ok(()) => {
+                self.metrics.record_send(None);
+                return Ok(());
+            }
+            Err(TrySendError::Full(value)) => value,
+            Err(TrySendError::Closed(value)) => return Err(SendError(value)),
+        };
+
+        // the queue is full, wait for the consumer
+        let start = Instant::now();
+        self.sender.send(value).await?;
+        let blocked_for = start.elapsed();
+        self.metrics.record_send(Some(blocked_for));
+
+        if blocked_for > SLOW_SEND_WARNING {
+            warn!(
+                "sending to queue {} blocked for {:?}, its consumer is not keeping up",
+                self.name, blocked_for
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct MeteredReceiver<T> {
+    receiver: Receiver<T>,
+    metrics: Arc<QueueMetrics>,
+}
+
+impl<T> MeteredReceiver<T> {
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        let poll = self.receiver.poll_recv(cx);
+        if let Poll::Ready(Some(_)) = poll {
+            self.metrics.record_receive();
+        }
+        poll
+    }
+}
+
+/// Metrics of the runtime, exported in the Prometheus text format
+#[derive(Debug)]
+pub struct Metrics {
+    command_queue: Arc<QueueMetrics>,
+    result_queue_size: usize,
+    result_queues: Mutex<BTreeMap<String, Arc<QueueMetrics>>>,
+}
+
+impl Metrics {
+    pub fn new(command_queue_size: usize, result_queue_size: usize) -> Self {
+        Self {
+            command_queue: Arc::new(QueueMetrics::new(command_queue_size)),
+            result_queue_size,
+            result_queues: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn command_queue(&self) -> Arc<QueueMetrics> {
+        self.command_queue.clone()
+    }
+
+    pub fn result_queue_size(&self) -> usize {
+        self.result_queue_size
+    }
+
+    /// The metrics of the async result queue of a module
+    pub fn result_queue(&self, module: &str) -> Arc<QueueMetrics> {
+        self.result_queues
+            .lock()
+            .unwrap()
+            .entry(module.to_string())
+            .or_insert_with(|| Arc::new(QueueMetrics::new(self.result_queue_size)))
+            .clone()
+    }
+
+    pub fn render(&self) -> String {
+        let result_queues = self.result_queues.lock().unwrap();
+        let queues =
+            std::iter::once(("queue=\"commands\"".to_string(), &self.command_queue))
+                .chain(result_queues.iter().map(|(module, queue)| {
+                    (format!("queue=\"results\",module=\"{}\"", module), queue)
+                }))
+                .collect::<Vec<_>>();
+
+        let mut out = String::new();
+        let mut family =
+            |name: &str, kind: &str, help: &str, value: &dyn Fn(&QueueMetrics) -> String| {
+                let _ = writeln!(out, "# HELP {} {}", name, help);
+                let _ = writeln!(out, "# TYPE {} {}", name, kind);
+                for (labels, queue) in queues.iter() {
+                    let _ = writeln!(out, "{}{{{}}} {}", name, labels, value(queue));
+                }
+            };
+
+        family(
+            "controller_queue_capacity",
+            "gauge",
+            "Capacity of the queue",
+            &|queue| queue.capacity.to_string(),
+        );
+        family(
+            "controller_queue_depth",
+            "gauge",
+            "Number of items waiting in the queue",
+            &|queue| queue.depth.load(Ordering::Relaxed).to_string(),
+        );
+        family(
+            "controller_queue_sent_total",
+            "counter",
+            "Number of items sent to the queue",
+            &|queue| queue.sent.load(Ordering::Relaxed).to_string(),
+        );
+        family(
+            "controller_queue_blocked_total",
+            "counter",
+            "Number of sends that waited because the queue was full",
+            &|queue| queue.blocked.load(Ordering::Relaxed).to_string(),
+        );
+        family(
+            "controller_queue_blocked_seconds_total",
+            "counter",
+            "Time senders spent waiting because the queue was full",
+            &|queue| {
+                (queue.blocked_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0).to_string()
+            },
+        );
+
+        out
+    }
+}
+
+/// Serve the metrics on `http://<addr>/metrics`
+pub async fn serve(addr: SocketAddr, metrics: Arc<Metrics>) -> anyhow::Result<()> {
+    let make_service = make_service_fn(move |_| {
+        let metrics = metrics.clone();
+        async move {
+            Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                let metrics = metrics.clone();
+                async move {
+                    let response = match (req.method(), req.uri().path()) {
+                        (&Method::GET, "/metrics") => Response::builder()
+                            .header("content-type", "text/plain; version=0.0.4")
+                            .body(Body::from(metrics.render())),
+                        _ => Response::builder()
+                            .status(StatusCode::NOT_FOUND)
+                            .body(Body::empty()),
+                    };
+                    Ok::<_, http::Error>(response?)
+                }
+            }))
+        }
+    });
+
+    info!("serving metrics on http://{}/metrics", addr);
+    hyper::Server::try_bind(&addr)?.serve(make_service).await?;
+
+    Ok(())
+}
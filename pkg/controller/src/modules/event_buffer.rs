@@ -0,0 +1,276 @@
+use super::object_cache::ObjectCache;
+use super::EventBufferConfig;
+use crate::abi::abicommand::AsyncResult;
+use crate::runtime::http_engine::watch_event::{object_key, with_event_type};
+use bytes::{Bytes, BytesMut};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// What a buffered event is coalesced with
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum ObjectKey {
+    Object(String),
+    Bookmark,
+    /// Errors and events that can't be parsed are never coalesced
+    Unique(usize),
+}
+
+struct BufferedEvent {
+    async_request_id: u64,
+    event_type: String,
+    line: Bytes,
+}
+
+/// Watch events received while a module is swapped out. Events of the same object are coalesced into
+/// the change the module would observe after all of them, so it is restored for a compacted set of changes
+/// instead of for every event. Changes that leave an object as the module last received it are dropped.
+pub(crate) struct EventBuffer {
+    max_events: usize,
+    max_delay: Duration,
+    first_buffered: Option<Instant>,
+    /// Events in the order of their last change, coalesced events leave an empty slot behind
+    events: Vec<Option<BufferedEvent>>,
+    index: HashMap<(u64, ObjectKey), usize>,
+    /// Incomplete last line of each stream
+    partial: HashMap<u64, BytesMut>,
+    nr_unique: usize,
+    /// The objects as the module received them, updated with every delivered chunk
+    cache: ObjectCache,
+}
+
+impl EventBuffer {
+    pub(crate) fn new(config: &EventBufferConfig) -> Self {
+        Self {
+            max_events: config.max_events,
+            max_delay: Duration::from_millis(config.max_delay_ms),
+            first_buffered: None,
+            events: Vec::new(),
+            index: HashMap::new(),
+            partial: HashMap::new(),
+            nr_unique: 0,
+            cache: ObjectCache::default(),
+        }
+    }
+
+    pub(crate) fn max_delay(&self) -> Duration {
+        self.max_delay
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.first_buffered.is_none()
+    }
+
+    /// The module has to be woken up to receive the buffered events
+    pub(crate) fn is_due(&self) -> bool {
+        match self.first_buffered {
+            None => false,
+            Some(first_buffered) => {
+                self.index.len() >= self.max_events || first_buffered.elapsed() >= self.max_delay
+            }
+        }
+    }
+
+    /// A chunk of a watch stream is delivered to the running module without buffering it
+    pub(crate) fn observe(&mut self, async_request_id: u64, chunk: &[u8]) {
+        self.cache.observe(async_request_id, chunk);
+    }
+
+    /// Forget the objects of the streams that ended or were cancelled
+    pub(crate) fn retain_streams(&mut self, is_streaming: impl Fn(u64) -> bool) {
+        self.cache.retain(is_streaming);
+    }
+
+    /// Buffer a chunk of a watch stream
+    pub(crate) fn push(&mut self, async_request_id: u64, chunk: Bytes) {
+        if self.first_buffered.is_none() {
+            self.first_buffered = Some(Instant::now());
+        }
+
+        let partial = self.partial.entry(async_request_id).or_default();
+        partial.extend_from_slice(&chunk);
+
+        let mut lines = Vec::new();
+        while let Some(pos) = partial.iter().position(|b| *b == b'\n') {
+            lines.push(partial.split_to(pos + 1).freeze());
+        }
+
+        for line in lines {
+            self.push_event(async_request_id, line);
+        }
+    }
+
+    fn push_event(&mut self, async_request_id: u64, line: Bytes) {
+        let event = serde_json::from_slice::<Value>(&line).unwrap_or_default();
+        let (key, event_type) = match event["type"].as_str() {
+            Some("BOOKMARK") => (ObjectKey::Bookmark, "BOOKMARK".to_string()),
+            Some(event_type) if event_type != "ERROR" => match object_key(&event["object"]) {
+                Some(key) => (ObjectKey::Object(key), event_type.to_string()),
+                None => (self.unique_key(), event_type.to_string()),
+            },
+            _ => (self.unique_key(), String::new()),
+        };
+
+        let previous = self
+            .index
+            .remove(&(async_request_id, key.clone()))
+            .and_then(|pos| self.events[pos].take());
+
+        let (event_type, line) = match previous.as_ref().map(|event| event.event_type.as_str()) {
+            // the module never saw the object
+            Some("ADDED") if event_type == "DELETED" => return,
+            Some("ADDED") if event_type == "MODIFIED" => {
+                ("ADDED".to_string(), with_event_type(&line, "ADDED"))
+            }
+            _ => (event_type, line),
+        };
+
+        self.index
+            .insert((async_request_id, key), self.events.len());
+        self.events.push(Some(BufferedEvent {
+            async_request_id,
+            event_type,
+            line,
+        }));
+    }
+
+    fn unique_key(&mut self) -> ObjectKey {
+        self.nr_unique += 1;
+        ObjectKey::Unique(self.nr_unique)
+    }
+
+    /// Take the coalesced events, followed by the incomplete lines, as results for the module
+    pub(crate) fn drain(&mut self) -> Vec<AsyncResult> {
+        self.first_buffered = None;
+        self.index.clear();
+
+        let mut results = Vec::new();
+        // the cache follows every delivered event, so each one is compared with the state before it
+        for event in self.events.drain(..).flatten() {
+            if self.cache.is_unchanged(event.async_request_id, &event.line) {
+                continue;
+            }
+            self.cache.observe(event.async_request_id, &event.line);
+            results.push((event.async_request_id, event.line));
+        }
+        for (async_request_id, partial) in self.partial.drain() {
+            if !partial.is_empty() {
+                self.cache.observe(async_request_id, &partial);
+                results.push((async_request_id, partial.freeze()));
+            }
+        }
+
+        results
+            .into_iter()
+            .map(|(async_request_id, value)| AsyncResult {
+                async_request_id,
+                value: Some(value),
+                finished: false,
+                error: None,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_type: &str, uid: &str, rv: &str) -> Bytes {
+        Bytes::from(format!(
+            "{{\"type\":\"{}\",\"object\":{{\"metadata\":{{\"uid\":\"{}\",\"resourceVersion\":\"{}\"}}}}}}\n",
+            event_type, uid, rv
+        ))
+    }
+
+    fn drained(buffer: &mut EventBuffer) -> Vec<(String, String)> {
+        buffer
+            .drain()
+            .into_iter()
+            .map(|result| {
+                let event: serde_json::Value =
+                    serde_json::from_slice(&result.value.unwrap()).unwrap();
+                (
+                    event["type"].as_str().unwrap().to_string(),
+                    event["object"]["metadata"]["resourceVersion"]
+                        .as_str()
+                        .unwrap()
+                        .to_string(),
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_coalesce() {
+        let mut buffer = EventBuffer::new(&EventBufferConfig {
+            max_events: 10,
+            max_delay_ms: 1000,
+        });
+
+        buffer.push(1, event("MODIFIED", "a", "1"));
+        buffer.push(1, event("ADDED", "b", "2"));
+        buffer.push(1, event("MODIFIED", "b", "3"));
+        buffer.push(1, event("ADDED", "c", "4"));
+        buffer.push(1, event("DELETED", "c", "5"));
+        buffer.push(1, event("MODIFIED", "a", "6"));
+
+        assert_eq!(
+            drained(&mut buffer),
+            vec![
+                ("ADDED".to_string(), "3".to_string()),
+                ("MODIFIED".to_string(), "6".to_string()),
+            ]
+        );
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_unchanged_objects() {
+        let mut buffer = EventBuffer::new(&EventBufferConfig {
+            max_events: 10,
+            max_delay_ms: 1000,
+        });
+
+        // received by the running module
+        buffer.observe(1, &event("ADDED", "a", "1"));
+        buffer.observe(1, &event("ADDED", "b", "2"));
+
+        // only the resource version of a changed, another watch did not deliver it yet
+        buffer.push(1, event("MODIFIED", "a", "3"));
+        buffer.push(1, event("DELETED", "b", "4"));
+        buffer.push(2, event("MODIFIED", "a", "5"));
+
+        assert_eq!(
+            drained(&mut buffer),
+            vec![
+                ("DELETED".to_string(), "4".to_string()),
+                ("MODIFIED".to_string(), "5".to_string()),
+            ]
+        );
+
+        // the module received the state of a from the drained events
+        buffer.push(2, event("MODIFIED", "a", "6"));
+        assert!(drained(&mut buffer).is_empty());
+    }
+
+    #[test]
+    fn test_split_lines() {
+        let mut buffer = EventBuffer::new(&EventBufferConfig {
+            max_events: 2,
+            max_delay_ms: 1000,
+        });
+
+        let line = event("MODIFIED", "a", "1");
+        buffer.push(1, line.slice(..10));
+        assert!(!buffer.is_due());
+        buffer.push(1, line.slice(10..));
+        buffer.push(1, event("MODIFIED", "b", "2").slice(..5));
+        buffer.push(2, event("MODIFIED", "a", "3"));
+        assert!(buffer.is_due());
+
+        let results = buffer.drain();
+        assert_eq!(results.len(), 3);
+        assert_eq!(results[2].value.as_deref(), Some(&b"{\"typ"[..]));
+    }
+}
@@ -1,4 +1,6 @@
+use crate::config::RateLimitConfig;
 use anyhow::Result;
+use http::{HeaderMap, HeaderValue};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 
@@ -8,6 +10,189 @@ pub struct EnvironmentVariable {
     pub value: String,
 }
 
+const IMPERSONATE_USER: &str = "impersonate-user";
+const IMPERSONATE_GROUP: &str = "impersonate-group";
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_connect_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_request_timeout_ms() -> u64 {
+    30_000
+}
+
+fn default_max_events() -> usize {
+    100
+}
+
+fn default_max_delay_ms() -> u64 {
+    5_000
+}
+
+/// Buffer the watch events of a swapped out module instead of restoring it for every event
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EventBufferConfig {
+    /// Restore the module once this many objects changed
+    #[serde(default = "default_max_events")]
+    pub max_events: usize,
+    /// Restore the module at the latest this long after the first buffered event
+    #[serde(default = "default_max_delay_ms")]
+    pub max_delay_ms: u64,
+}
+
+/// Share of the global rate limit the requests of a module can use,
+/// so modules with a lower priority can not exhaust it for those with a higher one
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Priority {
+    Low,
+    Normal,
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+/// Services outside of the cluster a module is allowed to call
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct OutboundHttpConfig {
+    /// Allowed origins `<scheme>://<host>[:<port>]`, the host may start with `*.` to allow its subdomains
+    pub allow: Vec<String>,
+    /// Refuse plain http origins
+    #[serde(default = "default_true")]
+    pub https_only: bool,
+    #[serde(default = "default_connect_timeout_ms")]
+    pub connect_timeout_ms: u64,
+    /// Timeout of the complete request, including reading the body
+    #[serde(default = "default_request_timeout_ms")]
+    pub request_timeout_ms: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ServiceAccountRef {
+    pub namespace: String,
+    pub name: String,
+}
+
+/// Kubernetes identity used for the requests of a module, instead of the identity of the host
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleIdentity {
+    /// Impersonate this ServiceAccount
+    pub service_account: Option<ServiceAccountRef>,
+    /// Impersonate this user
+    pub user: Option<String>,
+    /// Groups to impersonate together with `user`
+    #[serde(default)]
+    pub groups: Vec<String>,
+    /// Authenticate with the bearer token in this file instead of impersonating
+    pub token_file: Option<PathBuf>,
+}
+
+impl ModuleIdentity {
+    pub fn validate(&self) -> Result<()> {
+        let nr_identities = [
+            self.service_account.is_some(),
+            self.user.is_some(),
+            self.token_file.is_some(),
+        ]
+        .iter()
+        .filter(|set| **set)
+        .count();
+
+        if nr_identities != 1 {
+            anyhow::bail!("exactly one of serviceAccount, user and tokenFile must be set");
+        }
+
+        if !self.groups.is_empty() && self.user.is_none() {
+            anyhow::bail!("groups can only be impersonated together with a user");
+        }
+
+        Ok(())
+    }
+
+    /// The `Impersonate-*` headers to add to every request, empty when a token file is used
+    pub fn impersonation_headers(&self) -> Result<HeaderMap> {
+        let mut headers = HeaderMap::new();
+
+        if let Some(service_account) = &self.service_account {
+            // same user and groups as the API server assigns to a ServiceAccount token
+            headers.append(
+                IMPERSONATE_USER,
+                HeaderValue::from_str(&format!(
+                    "system:serviceaccount:{}:{}",
+                    service_account.namespace, service_account.name
+                ))?,
+            );
+            headers.append(
+                IMPERSONATE_GROUP,
+                HeaderValue::from_static("system:serviceaccounts"),
+            );
+            headers.append(
+                IMPERSONATE_GROUP,
+                HeaderValue::from_str(&format!(
+                    "system:serviceaccounts:{}",
+                    service_account.namespace
+                ))?,
+            );
+        }
+
+        if let Some(user) = &self.user {
+            headers.append(IMPERSONATE_USER, HeaderValue::from_str(user)?);
+            for group in self.groups.iter() {
+                headers.append(IMPERSONATE_GROUP, HeaderValue::from_str(group)?);
+            }
+        }
+
+        Ok(headers)
+    }
+}
+
+/// Kubernetes API requests a module is allowed to make, in the same terms as an RBAC `PolicyRule`.
+/// Every list may contain `*` to match anything.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PolicyRule {
+    /// API groups, `""` is the core group
+    pub api_groups: Vec<String>,
+    /// Resources, subresources are written as `<resource>/<subresource>` (e.g. `pods/log`)
+    pub resources: Vec<String>,
+    pub verbs: Vec<String>,
+    /// Namespaces the rule applies to, empty for all namespaces and cluster scoped resources
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+}
+
+/// Allowlist of the Kubernetes API requests of a module, a request must match at least one rule
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ModulePolicy {
+    pub rules: Vec<PolicyRule>,
+}
+
+impl ModulePolicy {
+    pub fn validate(&self) -> Result<()> {
+        for (i, rule) in self.rules.iter().enumerate() {
+            if rule.api_groups.is_empty() || rule.resources.is_empty() || rule.verbs.is_empty() {
+                anyhow::bail!(
+                    "rule {} of the policy must have apiGroups, resources and verbs",
+                    i
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ControllerModuleMetadata {
     pub name: String,
@@ -16,6 +201,22 @@ pub struct ControllerModuleMetadata {
     pub env: Vec<EnvironmentVariable>,
     #[serde(default)]
     pub args: Vec<String>,
+    #[serde(default, rename = "outboundHttp")]
+    pub outbound_http: Option<OutboundHttpConfig>,
+    #[serde(default)]
+    pub identity: Option<ModuleIdentity>,
+    #[serde(default)]
+    pub policy: Option<ModulePolicy>,
+    #[serde(default, rename = "eventBuffer")]
+    pub event_buffer: Option<EventBufferConfig>,
+    /// Overrides the module rate limit of the host config
+    #[serde(default, rename = "rateLimit")]
+    pub rate_limit: Option<RateLimitConfig>,
+    #[serde(default)]
+    pub priority: Priority,
+    /// Name of a cluster of the host config, the cluster the host is started with if not set
+    #[serde(default)]
+    pub cluster: Option<String>,
 }
 
 impl ControllerModuleMetadata {
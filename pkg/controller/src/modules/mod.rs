@@ -1,9 +1,19 @@
+mod event_buffer;
 mod metadata;
 mod module;
+mod object_cache;
 mod runner;
 mod wasm;
 
 pub use metadata::ControllerModuleMetadata;
+pub use metadata::EventBufferConfig;
+pub use metadata::ModuleIdentity;
+pub use metadata::ModulePolicy;
+pub use metadata::OutboundHttpConfig;
+pub use metadata::PolicyRule;
+pub use metadata::Priority;
 pub use module::ControllerModule;
 pub use runner::OpsRunner;
+pub use runner::RunnerOptions;
 pub use wasm::WasmRuntime;
+pub use wasm::WASM_PAGE_SIZE;
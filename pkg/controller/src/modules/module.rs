@@ -1,6 +1,7 @@
+use super::event_buffer::EventBuffer;
+use super::EventBufferConfig;
 use super::OpsRunner;
 use super::WasmRuntime;
-use crate::runtime::COMPILE_WITH_UNINSTANTIATE;
 use chrono::DateTime;
 use chrono::Duration;
 use chrono::Utc;
@@ -41,6 +42,8 @@ pub struct ControllerModule {
     predicted_wakeup: ServerResp,
     sleep_vec: Vec<Pin<Box<Sleep>>>,
     first_event_after_shutdown: bool,
+    swapping: bool,
+    event_buffer: Option<EventBuffer>,
 }
 
 // How this works: variables: Last event (when the last event was i.e last async request), SHUTDOWN_INACTIVE_INTERVAL_MS is time of inactivity from last event when we want to shutdown, TIME_BEFORE_PREDICTED_MS is the time before the predicted next wakeup
@@ -52,7 +55,12 @@ pub struct ControllerModule {
 // we do cx.waker().wake_by_ref(); to wake up the poll, and importantly wake up wasm work when we set it, always do a wake after it!
 
 impl ControllerModule {
-    pub(crate) fn new(wasm: WasmRuntime, ops_runner: Arc<Mutex<OpsRunner>>) -> Self {
+    pub(crate) fn new(
+        wasm: WasmRuntime,
+        ops_runner: Arc<Mutex<OpsRunner>>,
+        swapping: bool,
+        event_buffer: Option<&EventBufferConfig>,
+    ) -> Self {
         debug!("doing new");
 
         let mut last_events = VecDeque::with_capacity(BUFFER_LENGTH);
@@ -77,6 +85,8 @@ impl ControllerModule {
             sleep_vec,
             first_event_after_shutdown,
             last_event_time,
+            swapping,
+            event_buffer: event_buffer.map(EventBuffer::new),
         }
     }
 
@@ -152,7 +162,7 @@ impl ControllerModule {
 
         if runner.nr_web_calls == 0
             && !self.wasm.is_uninstantiating()
-            && *COMPILE_WITH_UNINSTANTIATE
+            && self.swapping
             // only shutdown not direct but after x milliseconds of inactive
             && is_inactive_period(&current_time, &self.last_event_time)
             // do not shut down when we see in the future predicted is coming
@@ -213,7 +223,7 @@ impl ControllerModule {
     }
 
     fn resolve_async_ops(&mut self, cx: &mut Context) -> anyhow::Result<bool> {
-        let maybe_result = {
+        let results = {
             // WASM is not running, so the lock will not delay a new op from being added using 'handle_request'
             let mut runner = self.ops_runner.lock().unwrap();
             //debug!("doing resolve async nr calls : {:?} and unpolled {:?} is active {:?}", runner.nr_web_calls,runner.have_unpolled_ops,!self.wasm.is_uninstantiating());
@@ -221,7 +231,7 @@ impl ControllerModule {
             runner.have_unpolled_ops = false;
 
             // Check if any async requests errored
-            loop {
+            let (results, is_event) = loop {
                 let poll_result = runner.pending_ops.poll_next_unpin(cx);
 
                 if let Poll::Ready(Some(Ok(val))) = poll_result {
@@ -231,24 +241,94 @@ impl ControllerModule {
                 }
 
                 if let Poll::Ready(Some(Err(err))) = poll_result {
-                    //should never happen, currently error handeling is not well implemented I think, if web connection times out we crash
-                    runner.nr_web_calls -= 0;
-                    //break None;
+                    // transport errors are delivered to the guest as failed results, an op only fails
+                    // if its result can not be delivered anymore
                     return Err(err);
                 }
 
                 if let Poll::Ready(Some(result)) = runner.async_result_rx.poll_recv(cx) {
-                    break Some(result);
+                    // deliver everything that is ready in a single wakeup
+                    let mut results = vec![result];
+                    while let Poll::Ready(Some(result)) = runner.async_result_rx.poll_recv(cx) {
+                        results.push(result);
+                    }
+
+                    let is_event = results
+                        .iter()
+                        .map(|result| {
+                            runner.is_stream_chunk(result.async_request_id)
+                                && !result.finished
+                                && result.error.is_none()
+                                && result.value.is_some()
+                        })
+                        .collect::<Vec<bool>>();
+
+                    for result in results.iter().filter(|result| result.finished) {
+                        runner.request_finished(result.async_request_id);
+                    }
+                    break (results, is_event);
                 }
 
                 if let Poll::Ready(None) | Poll::Pending = poll_result {
-                    break None;
+                    break (Vec::new(), Vec::new());
+                }
+            };
+
+            if let Some(buffer) = &mut self.event_buffer {
+                buffer.retain_streams(|async_request_id| runner.is_streaming(async_request_id));
+            }
+
+            match &mut self.event_buffer {
+                // while swapped out, watch events are buffered instead of restoring the module for each of them
+                Some(buffer) if self.wasm.is_uninstantiating() => {
+                    let was_empty = buffer.is_empty();
+
+                    let mut others = Vec::new();
+                    for (result, is_event) in results.into_iter().zip(is_event) {
+                        if is_event {
+                            buffer.push(result.async_request_id, result.value.expect("checked"));
+                        } else {
+                            others.push(result);
+                        }
+                    }
+
+                    if was_empty && !buffer.is_empty() {
+                        debug!("buffering watch events while swapped out");
+                        let mut sleep = Box::pin(tokio::time::sleep(buffer.max_delay()));
+                        sleep.poll_unpin(cx);
+                        self.sleep_vec.push(sleep);
+                    }
+
+                    // other results need the module anyway, deliver the buffered events with them
+                    if others.is_empty() && !buffer.is_due() {
+                        others
+                    } else {
+                        let mut results = buffer.drain();
+                        results.extend(others);
+                        results
+                    }
+                }
+                Some(buffer) => {
+                    // e.g. restored because of a predicted event
+                    let mut delivered = if buffer.is_empty() {
+                        Vec::new()
+                    } else {
+                        buffer.drain()
+                    };
+                    for (result, is_event) in results.iter().zip(is_event) {
+                        if let (true, Some(value)) = (is_event, &result.value) {
+                            buffer.observe(result.async_request_id, value);
+                        }
+                    }
+                    delivered.extend(results);
+                    delivered
                 }
+                None => results,
             }
         };
 
         // Retrieve async request results & start wasm again
-        if let Some(result) = maybe_result {
+        if !results.is_empty() {
             // use wakeup timings instead of requests
             if self.first_event_after_shutdown {
                 self.first_event_after_shutdown = false;
@@ -273,7 +353,7 @@ impl ControllerModule {
             }
 
             // wake up after unactive interval
-            if result.finished {
+            if results.iter().any(|result| result.finished) {
                 self.last_event_time = Utc::now();
                 let mut sleep = Box::pin(tokio::time::sleep(Durationtk::from_millis(
                     (SHUTDOWN_INACTIVE_INTERVAL_MS + 10) as u64,
@@ -282,8 +362,8 @@ impl ControllerModule {
                 self.sleep_vec.push(sleep);
             }
 
-            self.wasm
-                .wakeup(result.async_request_id, result.value, result.finished)?;
+            debug!("waking up module with {} results", results.len());
+            self.wasm.wakeup(results)?;
 
             Ok(true)
         } else {
@@ -0,0 +1,88 @@
+use crate::runtime::http_engine::watch_event::object_key;
+use bytes::BytesMut;
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// The state of an object, without the metadata that changes with every write
+fn object_state(object: &Value) -> Value {
+    let mut state = object.clone();
+    if let Some(metadata) = state.get_mut("metadata").and_then(Value::as_object_mut) {
+        metadata.remove("resourceVersion");
+        metadata.remove("managedFields");
+    }
+    state
+}
+
+#[derive(Default)]
+struct StreamObjects {
+    /// Incomplete last line the module received
+    partial: BytesMut,
+    objects: HashMap<String, Value>,
+}
+
+impl StreamObjects {
+    fn apply(&mut self, line: &[u8]) {
+        let event = match serde_json::from_slice::<Value>(line) {
+            Ok(event) => event,
+            Err(_) => return,
+        };
+        // bookmarks and errors are not about an object
+        let key = match object_key(&event["object"]) {
+            Some(key) => key,
+            None => return,
+        };
+
+        match event["type"].as_str() {
+            Some("ADDED") | Some("MODIFIED") => {
+                self.objects.insert(key, object_state(&event["object"]));
+            }
+            Some("DELETED") => {
+                self.objects.remove(&key);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The watched objects of a module, as the module last received them from each watch stream.
+/// Objects the module only received in a list response are not known.
+#[derive(Default)]
+pub(crate) struct ObjectCache {
+    streams: HashMap<u64, StreamObjects>,
+}
+
+impl ObjectCache {
+    /// Update the cache with a chunk of a watch stream, as it is delivered to the module
+    pub(crate) fn observe(&mut self, async_request_id: u64, chunk: &[u8]) {
+        let stream = self.streams.entry(async_request_id).or_default();
+        stream.partial.extend_from_slice(chunk);
+
+        while let Some(pos) = stream.partial.iter().position(|b| *b == b'\n') {
+            let line = stream.partial.split_to(pos + 1);
+            stream.apply(&line);
+        }
+    }
+
+    /// Whether the module already has the state of the object of an `ADDED` or `MODIFIED` event
+    pub(crate) fn is_unchanged(&self, async_request_id: u64, line: &[u8]) -> bool {
+        let stream = match self.streams.get(&async_request_id) {
+            Some(stream) => stream,
+            None => return false,
+        };
+        let event = match serde_json::from_slice::<Value>(line) {
+            Ok(event) => event,
+            Err(_) => return false,
+        };
+
+        matches!(event["type"].as_str(), Some("ADDED") | Some("MODIFIED"))
+            && object_key(&event["object"])
+                .and_then(|key| stream.objects.get(&key))
+                .map_or(false, |cached| *cached == object_state(&event["object"]))
+    }
+
+    /// Forget the objects of streams that ended or were cancelled
+    pub(crate) fn retain(&mut self, is_streaming: impl Fn(u64) -> bool) {
+        self.streams
+            .retain(|async_request_id, _| is_streaming(*async_request_id));
+    }
+}
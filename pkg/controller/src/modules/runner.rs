@@ -1,38 +1,85 @@
 use crate::abi::abicommand::AsyncResult;
 use crate::abi::opcall::OpCall;
-use crate::abi::AsyncRequestValue;
+use crate::abi::{AsyncRequestValue, HostError, HostErrorKind};
 use crate::kube_client::KubeClientService;
+use crate::metrics::{self, MeteredReceiver, MeteredSender, Metrics};
+use crate::modules::ModulePolicy;
+use crate::runtime::http_engine::outbound::OutboundClient;
+use crate::runtime::http_engine::policy::forbidden_response;
 use crate::runtime::http_engine::request_executor::start_request_executor;
+use crate::runtime::http_engine::watch_filter::StreamFilter;
+use crate::runtime::http_engine::watch_multiplexer::WatchMultiplexer;
+use futures::future::{AbortHandle, Abortable, BoxFuture};
 use futures::stream::futures_unordered::FuturesUnordered;
-use futures::StreamExt;
-use tokio::sync::mpsc::Receiver;
-use tokio::sync::mpsc::Sender;
-use tracing::debug;
+use futures::{FutureExt, StreamExt};
+use http::HeaderMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tracing::{debug, warn};
+
+/// How the requests of a module are executed and which of them are allowed
+pub struct RunnerOptions {
+    pub(crate) service: KubeClientService,
+    /// `None` if the watches of the module can't be shared
+    pub(crate) watch_multiplexer: Option<Arc<WatchMultiplexer>>,
+    pub(crate) outbound_client: Option<Arc<OutboundClient>>,
+    pub(crate) impersonation: Option<HeaderMap>,
+    pub(crate) policy: Option<ModulePolicy>,
+}
 
 pub struct OpsRunner {
     name: String,
     cluster_url: http::Uri,
     service: KubeClientService,
+    watch_multiplexer: Option<Arc<WatchMultiplexer>>,
+    outbound_client: Option<Arc<OutboundClient>>,
+    impersonation: Option<Arc<HeaderMap>>,
+    policy: Option<ModulePolicy>,
 
     pub(crate) pending_ops: FuturesUnordered<OpCall<anyhow::Result<bool>>>,
     pub(crate) have_unpolled_ops: bool,
     pub(crate) nr_web_calls: usize,
+    abort_handles: HashMap<u64, AbortHandle>,
+    /// Streamed requests, and whether their response meta was received
+    stream_requests: HashMap<u64, bool>,
 
-    pub(crate) async_result_rx: Receiver<AsyncResult>,
-    async_result_tx: Sender<AsyncResult>,
+    pub(crate) async_result_rx: MeteredReceiver<AsyncResult>,
+    async_result_tx: MeteredSender<AsyncResult>,
 }
 
 impl OpsRunner {
-    pub(crate) fn new(name: String, cluster_url: http::Uri, service: KubeClientService) -> Self {
-        let (async_result_tx, async_result_rx) = tokio::sync::mpsc::channel(10);
+    pub(crate) fn new(
+        name: String,
+        cluster_url: http::Uri,
+        options: RunnerOptions,
+        metrics: &Metrics,
+    ) -> Self {
+        let RunnerOptions {
+            service,
+            watch_multiplexer,
+            outbound_client,
+            impersonation,
+            policy,
+        } = options;
+        let (async_result_tx, async_result_rx) = metrics::metered_channel(
+            &format!("results of {}", name),
+            metrics.result_queue_size(),
+            metrics.result_queue(&name),
+        );
         Self {
             name,
             cluster_url,
             service,
+            watch_multiplexer,
+            outbound_client,
+            impersonation: impersonation.map(Arc::new),
+            policy,
 
             pending_ops: FuturesUnordered::new(),
             have_unpolled_ops: false,
             nr_web_calls: 0,
+            abort_handles: HashMap::new(),
+            stream_requests: HashMap::new(),
 
             async_result_rx,
             async_result_tx,
@@ -45,117 +92,393 @@ impl OpsRunner {
         self.have_unpolled_ops = true;
     }
 
-    pub(crate) fn handle_request(&mut self, async_request_id: u64, request: AsyncRequestValue) {
-        let name = self.name.clone();
-        let result_sender = self.async_result_tx.clone();
-        let cluster_url = self.cluster_url.clone();
-        let service = self.service.clone();
+    /// Abort a request the guest is not interested in anymore, its pending results are ignored by the guest
+    pub(crate) fn cancel(&mut self, async_request_id: u64) {
+        self.stream_requests.remove(&async_request_id);
+        if let Some(abort_handle) = self.abort_handles.remove(&async_request_id) {
+            debug!("cancelling request {} of {}", async_request_id, self.name);
+            abort_handle.abort();
+        }
+    }
 
-        if let AsyncRequestValue::Http(_) = request {
-            self.nr_web_calls += 1;
+    /// Forget the abort handle of a request once its last result is received
+    pub(crate) fn request_finished(&mut self, async_request_id: u64) {
+        self.abort_handles.remove(&async_request_id);
+        self.stream_requests.remove(&async_request_id);
+    }
+
+    /// Whether a streamed request is still running
+    pub(crate) fn is_streaming(&self, async_request_id: u64) -> bool {
+        self.stream_requests.contains_key(&async_request_id)
+    }
+
+    /// Whether a result is a chunk of a streamed response (e.g. watch events) rather than its meta,
+    /// has to be called for every received result in order
+    pub(crate) fn is_stream_chunk(&mut self, async_request_id: u64) -> bool {
+        match self.stream_requests.get_mut(&async_request_id) {
+            Some(meta_received) => std::mem::replace(meta_received, true),
+            None => false,
         }
+    }
 
-        debug!("calling handle request");
+    /// Fail a request without executing it, the error is delivered to the guest through `wakeup`
+    pub(crate) fn handle_error(&mut self, async_request_id: u64, error: HostError) {
+        let result_sender = self.async_result_tx.clone();
 
-        self.handle_opcall(match request {
-            AsyncRequestValue::Http(value) => OpCall::eager(async move {
-                debug!(
-                    "Received request command from {} with id {}: {} {:?}",
-                    name,
-                    &async_request_id,
-                    value.method().as_str(),
-                    value.uri()
-                );
+        debug!(
+            "request {} of {} failed: {}",
+            async_request_id, self.name, error
+        );
 
-                let (meta, body) = start_request_executor(value, cluster_url, service).await?;
+        self.handle_opcall(OpCall::eager(async move {
+            result_sender
+                .send(AsyncResult::failed(async_request_id, error))
+                .await?;
 
-                result_sender
-                    .clone()
-                    .send(AsyncResult {
-                        async_request_id,
-                        value: Some(bytes::Bytes::from(bincode::serialize(&meta)?)),
-                        finished: false,
-                    })
-                    .await?;
+            Ok(false)
+        }));
+    }
+
+    /// Answer a request that is not allowed by the policy of the module with a `403 Forbidden`,
+    /// so the guest handles it like any other API error
+    fn handle_forbidden(&mut self, async_request_id: u64, reason: String, stream: bool) {
+        let result_sender = self.async_result_tx.clone();
+
+        warn!(
+            "request {} of {} denied: {}",
+            async_request_id, self.name, reason
+        );
 
-                drop(meta);
+        self.handle_opcall(OpCall::eager(async move {
+            let (meta, body) = forbidden_response(&reason);
 
-                let full_body = hyper::body::to_bytes(body).await;
+            result_sender
+                .send(AsyncResult {
+                    async_request_id,
+                    value: Some(bytes::Bytes::from(bincode::serialize(&meta)?)),
+                    finished: false,
+                    error: None,
+                })
+                .await?;
 
+            result_sender
+                .send(AsyncResult {
+                    async_request_id,
+                    value: Some(body),
+                    finished: !stream,
+                    error: None,
+                })
+                .await?;
+
+            // streams end with a finished result without payload
+            if stream {
                 result_sender
-                    .clone()
                     .send(AsyncResult {
                         async_request_id,
-                        value: Some(full_body?),
+                        value: None,
                         finished: true,
+                        error: None,
                     })
                     .await?;
+            }
+
+            Ok(false)
+        }));
+    }
+
+    pub(crate) fn handle_request(&mut self, async_request_id: u64, request: AsyncRequestValue) {
+        let name = self.name.clone();
+        let result_sender = self.async_result_tx.clone();
+        let cluster_url = self.cluster_url.clone();
+        let service = self.service.clone();
+        let watch_multiplexer = self.watch_multiplexer.clone();
+        let outbound_client = self.outbound_client.clone();
+        let impersonation = self.impersonation.clone();
+
+        if let AsyncRequestValue::Outbound(value) = &request {
+            let allowed = match &outbound_client {
+                Some(outbound_client) => outbound_client.check(value.uri()),
+                None => Err("outbound HTTP is not configured for this module".to_string()),
+            };
 
-                Ok(true)
-            }),
-            AsyncRequestValue::HttpStream(value) => OpCall::eager(async move {
-                debug!(
-                    "Received stream request command from {} with id {}: {} {:?}",
-                    name,
-                    &async_request_id,
-                    value.method().as_str(),
-                    value.uri()
+            if let Err(reason) = allowed {
+                return self.handle_error(
+                    async_request_id,
+                    HostError::new(HostErrorKind::Denied, reason),
                 );
+            }
+        }
 
-                let (meta, mut body) = start_request_executor(value, cluster_url, service).await?;
+        if let Some(policy) = &self.policy {
+            if let AsyncRequestValue::Http(value) | AsyncRequestValue::HttpStream(value, _) =
+                &request
+            {
+                if let Err(reason) = policy.check(value.method(), value.uri()) {
+                    let stream = matches!(request, AsyncRequestValue::HttpStream(..));
+                    return self.handle_forbidden(async_request_id, reason, stream);
+                }
+            }
+        }
 
-                result_sender
-                    .clone()
-                    .send(AsyncResult {
-                        async_request_id,
-                        value: Some(bytes::Bytes::from(bincode::serialize(&meta)?)),
-                        finished: false,
-                    })
-                    .await?;
+        if let AsyncRequestValue::HttpStream(..) = &request {
+            self.stream_requests.insert(async_request_id, false);
+        }
+
+        let is_web_call = matches!(
+            request,
+            AsyncRequestValue::Http(_) | AsyncRequestValue::Outbound(_)
+        );
+        if is_web_call {
+            self.nr_web_calls += 1;
+        }
 
-                drop(meta);
+        debug!("calling handle request");
+
+        let op: BoxFuture<'static, anyhow::Result<bool>> =
+            match request {
+                AsyncRequestValue::Http(value) => async move {
+                    debug!(
+                        "Received request command from {} with id {}: {} {:?}",
+                        name,
+                        &async_request_id,
+                        value.method().as_str(),
+                        value.uri()
+                    );
+
+                    let (meta, body) =
+                        match start_request_executor(value, cluster_url, service, impersonation)
+                            .await
+                        {
+                            Ok(response) => response,
+                            Err(err) => {
+                                send_transport_error(&result_sender, async_request_id, err).await?;
+                                return Ok(true);
+                            }
+                        };
 
-                while let Some(chunk) = body.next().await {
                     result_sender
                         .clone()
                         .send(AsyncResult {
                             async_request_id,
-                            value: Some(chunk?),
+                            value: Some(bytes::Bytes::from(bincode::serialize(&meta)?)),
                             finished: false,
+                            error: None,
                         })
                         .await?;
+
+                    drop(meta);
+
+                    match hyper::body::to_bytes(body).await {
+                        Ok(full_body) => {
+                            result_sender
+                                .clone()
+                                .send(AsyncResult {
+                                    async_request_id,
+                                    value: Some(full_body),
+                                    finished: true,
+                                    error: None,
+                                })
+                                .await?
+                        }
+                        Err(err) => {
+                            send_transport_error(&result_sender, async_request_id, err.into())
+                                .await?
+                        }
+                    }
+
+                    Ok(true)
                 }
+                .boxed(),
+                AsyncRequestValue::HttpStream(value, filter) => async move {
+                    debug!(
+                        "Received stream request command from {} with id {}: {} {:?}",
+                        name,
+                        &async_request_id,
+                        value.method().as_str(),
+                        value.uri()
+                    );
 
-                result_sender
-                    .clone()
-                    .send(AsyncResult {
-                        async_request_id,
-                        value: None,
-                        finished: true,
-                    })
-                    .await?;
+                    let response = match watch_multiplexer {
+                        // identical watches of other modules share a single upstream watch
+                        Some(watch_multiplexer) if watch_multiplexer.is_shareable(&value) => {
+                            watch_multiplexer.watch(value, service, impersonation).await
+                        }
+                        _ => start_request_executor(value, cluster_url, service, impersonation)
+                            .await
+                            .map(|(meta, body)| {
+                                (
+                                    meta,
+                                    body.map(|chunk| chunk.map_err(anyhow::Error::from)).boxed(),
+                                )
+                            }),
+                    };
 
-                Ok(false)
-            }),
-            AsyncRequestValue::Delay(value) => OpCall::eager(async move {
-                debug!(
-                    "Received delay command from with id {}: {:?}",
-                    &async_request_id, value
-                );
+                    let (meta, mut body) = match response {
+                        Ok(response) => response,
+                        Err(err) => {
+                            send_transport_error(&result_sender, async_request_id, err).await?;
+                            return Ok(false);
+                        }
+                    };
 
-                tokio::time::sleep(value).await;
+                    result_sender
+                        .clone()
+                        .send(AsyncResult {
+                            async_request_id,
+                            value: Some(bytes::Bytes::from(bincode::serialize(&meta)?)),
+                            finished: false,
+                            error: None,
+                        })
+                        .await?;
 
-                result_sender
-                    .clone()
-                    .send(AsyncResult {
-                        async_request_id,
-                        value: None,
-                        finished: true,
-                    })
-                    .await?;
+                    // error responses are not watch events
+                    let mut filter = filter
+                        .filter(|_| meta.status_code.is_success())
+                        .map(StreamFilter::new);
+
+                    drop(meta);
+
+                    while let Some(chunk) = body.next().await {
+                        let chunk = match chunk {
+                            Ok(chunk) => chunk,
+                            Err(err) => {
+                                // e.g. the watch connection was reset, the guest can restart it
+                                send_transport_error(&result_sender, async_request_id, err).await?;
+                                return Ok(false);
+                            }
+                        };
+
+                        // events the module is not interested in don't wake it up
+                        let chunk = match &mut filter {
+                            Some(filter) => match filter.apply(&chunk) {
+                                Some(chunk) => chunk,
+                                None => continue,
+                            },
+                            None => chunk,
+                        };
+
+                        result_sender
+                            .clone()
+                            .send(AsyncResult {
+                                async_request_id,
+                                value: Some(chunk),
+                                finished: false,
+                                error: None,
+                            })
+                            .await?;
+                    }
+
+                    if let Some(rest) = filter.as_mut().and_then(StreamFilter::finish) {
+                        result_sender
+                            .send(AsyncResult {
+                                async_request_id,
+                                value: Some(rest),
+                                finished: false,
+                                error: None,
+                            })
+                            .await?;
+                    }
+
+                    result_sender
+                        .clone()
+                        .send(AsyncResult {
+                            async_request_id,
+                            value: None,
+                            finished: true,
+                            error: None,
+                        })
+                        .await?;
+
+                    Ok(false)
+                }
+                .boxed(),
+                AsyncRequestValue::Outbound(value) => async move {
+                    debug!(
+                        "Received outbound request command from {} with id {}: {} {:?}",
+                        name,
+                        &async_request_id,
+                        value.method().as_str(),
+                        value.uri()
+                    );
 
-                Ok(false)
-            }),
-        });
+                    let outbound_client = outbound_client.expect("checked before");
+                    let (meta, body) = match outbound_client.execute(value).await {
+                        Ok(response) => response,
+                        Err(err) => {
+                            send_transport_error(&result_sender, async_request_id, err).await?;
+                            return Ok(true);
+                        }
+                    };
+
+                    result_sender
+                        .send(AsyncResult {
+                            async_request_id,
+                            value: Some(bytes::Bytes::from(bincode::serialize(&meta)?)),
+                            finished: false,
+                            error: None,
+                        })
+                        .await?;
+
+                    result_sender
+                        .send(AsyncResult {
+                            async_request_id,
+                            value: Some(body),
+                            finished: true,
+                            error: None,
+                        })
+                        .await?;
+
+                    Ok(true)
+                }
+                .boxed(),
+                AsyncRequestValue::Delay(value) => async move {
+                    debug!(
+                        "Received delay command from with id {}: {:?}",
+                        &async_request_id, value
+                    );
+
+                    tokio::time::sleep(value).await;
+
+                    result_sender
+                        .clone()
+                        .send(AsyncResult {
+                            async_request_id,
+                            value: None,
+                            finished: true,
+                            error: None,
+                        })
+                        .await?;
+
+                    Ok(false)
+                }
+                .boxed(),
+            };
+
+        // the guest can cancel the request, e.g. when it drops a watch stream
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        self.abort_handles.insert(async_request_id, abort_handle);
+
+        self.handle_opcall(OpCall::eager(
+            Abortable::new(op, abort_registration)
+                .map(move |result| result.unwrap_or(Ok(is_web_call))),
+        ));
     }
 }
+
+/// Deliver a failed connection, timeout or body error to the guest as the result of its request
+async fn send_transport_error(
+    result_sender: &MeteredSender<AsyncResult>,
+    async_request_id: u64,
+    err: anyhow::Error,
+) -> anyhow::Result<()> {
+    warn!("request {} failed: {:#}", async_request_id, err);
+
+    result_sender
+        .send(AsyncResult::failed(
+            async_request_id,
+            HostError::new(HostErrorKind::Transport, format!("{:#}", err)),
+        ))
+        .await?;
+
+    Ok(())
+}
@@ -1,5 +1,7 @@
+use crate::abi::abicommand::AsyncResult;
+use crate::abi::version::AbiVersion;
 use crate::runtime::controller_ctx::ControllerCtx;
-use crate::runtime::Environment;
+use crate::runtime::{load_module, Environment};
 use futures::future::BoxFuture;
 use futures::FutureExt;
 use log::debug;
@@ -14,9 +16,9 @@ use tokio::sync::Mutex as AsyncMutex;
 use tokio::sync::OwnedSemaphorePermit as AsyncOwnedSemaphorePermit;
 use tokio::sync::Semaphore as AsyncSemaphore;
 use tracing::Instrument;
-use wasmtime::{Instance, Module, Store};
+use wasmtime::{Instance, Store};
 
-const WASM_PAGE_SIZE: u64 = 0x10000;
+pub const WASM_PAGE_SIZE: u64 = 0x10000;
 
 pub struct Snapshot {
     pub globals: Vec<(String, wasmtime::Val)>,
@@ -86,8 +88,11 @@ pub struct WasmRuntime {
     uninstantiating: bool,
 
     wasm_path: std::path::PathBuf,
+    /// The wasm file the precompiled module at `wasm_path` is compiled from
+    source_path: std::path::PathBuf,
     swap_path: std::path::PathBuf,
     environment: Environment,
+    abi_version: AbiVersion,
 
     async_active_client_counter: Arc<AsyncSemaphore>,
 }
@@ -112,7 +117,9 @@ impl fmt::Debug for WasmRuntime {
 impl WasmRuntime {
     pub(crate) fn new(
         controller_ctx: ControllerCtx,
+        abi_version: AbiVersion,
         wasm_path: std::path::PathBuf,
+        source_path: std::path::PathBuf,
         swap_path: std::path::PathBuf,
         environment: Environment,
         async_active_client_counter: Arc<AsyncSemaphore>,
@@ -122,8 +129,10 @@ impl WasmRuntime {
             wasm_work: None,
             uninstantiating: true,
             wasm_path,
+            source_path,
             swap_path,
             environment,
+            abi_version,
             async_active_client_counter,
         }
     }
@@ -198,6 +207,7 @@ impl WasmRuntime {
         let arc = self.inner.clone();
         let environment = self.environment.clone();
         let wasm_path = self.wasm_path.clone();
+        let source_path = self.source_path.clone();
         let async_active_client_counter_clone = self.async_active_client_counter.clone();
 
         let fut = async move {
@@ -208,7 +218,7 @@ impl WasmRuntime {
 
                 let mut store = Store::new(&environment.engine, context);
 
-                let module = unsafe { Module::deserialize_file(&environment.engine, &wasm_path)? };
+                let module = load_module(&environment, &wasm_path, &source_path)?;
 
                 let pre_instance = environment.linker.instantiate_pre(&mut store, &module)?;
 
@@ -236,17 +246,27 @@ impl WasmRuntime {
         Ok(())
     }
 
-    pub(crate) fn wakeup(
-        &mut self,
-        async_request_id: u64,
-        value: Option<bytes::Bytes>,
-        finished: bool,
-    ) -> anyhow::Result<()> {
+    pub(crate) fn wakeup(&mut self, results: Vec<AsyncResult>) -> anyhow::Result<()> {
         assert!(self.wasm_work.is_none());
+
+        for result in &results {
+            if let Some(error) = &result.error {
+                if !self.abi_version.supports_error_channel() {
+                    anyhow::bail!(
+                        "request {} failed and ABI {} can not report errors to the module: {}",
+                        result.async_request_id,
+                        self.abi_version,
+                        error
+                    );
+                }
+            }
+        }
+
         let arc = self.inner.clone();
         let environment = self.environment.clone();
         let swap_path = self.swap_path.clone();
         let wasm_path = self.wasm_path.clone();
+        let source_path = self.source_path.clone();
         let async_active_client_counter_clone = self.async_active_client_counter.clone();
 
         let fut = async move {
@@ -257,7 +277,7 @@ impl WasmRuntime {
 
                 let permit = async_active_client_counter_clone.acquire_owned().await?;
 
-                let module = unsafe { Module::deserialize_file(&environment.engine, &wasm_path)? };
+                let module = load_module(&environment, &wasm_path, &source_path)?;
 
                 let mut store = Store::new(&environment.engine, context);
                 let pre_instance = environment.linker.instantiate_pre(&mut store, &module)?;
@@ -301,7 +321,7 @@ impl WasmRuntime {
                 _ => unreachable!(),
             };
 
-            crate::abi::wakeup(store, instance, async_request_id, value, finished).await?;
+            crate::abi::wakeup(store, instance, results).await?;
 
             Ok(())
         }
@@ -320,6 +340,7 @@ impl WasmRuntime {
         let environment = self.environment.clone();
         let swap_path = self.swap_path.clone();
         let wasm_path = self.wasm_path.clone();
+        let source_path = self.source_path.clone();
         let async_active_client_counter_clone = self.async_active_client_counter.clone();
 
         let fut = async move {
@@ -331,7 +352,7 @@ impl WasmRuntime {
 
                 let permit = async_active_client_counter_clone.acquire_owned().await?;
 
-                let module = unsafe { Module::deserialize_file(&environment.engine, &wasm_path)? };
+                let module = load_module(&environment, &wasm_path, &source_path)?;
 
                 let mut store = Store::new(&environment.engine, context);
                 let pre_instance = environment.linker.instantiate_pre(&mut store, &module)?;
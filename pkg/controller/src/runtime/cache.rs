@@ -0,0 +1,143 @@
+use super::Environment;
+use anyhow::{Context, Result};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use tracing::{debug, info, warn};
+use wasmtime::Module;
+
+const CACHE_EXTENSION: &str = "cwasm";
+
+/// Cache of precompiled modules.
+///
+/// Entries are named `<wasm hash>.<engine fingerprint>.cwasm`, so a module compiled by a
+/// differently configured engine (or an other version of the controller or wasmtime) is never loaded.
+/// The cache can be filled ahead of time using the `precompile` command.
+pub struct ModuleCache {
+    dir: PathBuf,
+    fingerprint: String,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl ModuleCache {
+    pub fn new(dir: PathBuf, environment: &Environment) -> Result<Self> {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create cache dir {}", dir.display()))?;
+
+        Ok(Self {
+            dir,
+            fingerprint: environment.fingerprint.clone(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    /// Path of the cache entry for a wasm file
+    pub fn entry_path(&self, wasm_bytes: &[u8]) -> PathBuf {
+        let wasm_hash = blake3::hash(wasm_bytes).to_hex();
+
+        self.dir.join(format!(
+            "{}.{}.{}",
+            wasm_hash, self.fingerprint, CACHE_EXTENSION
+        ))
+    }
+
+    /// Return the path of the precompiled module, compiling it if it is not in the cache yet
+    pub fn get_or_compile(&self, environment: &Environment, wasm_path: &Path) -> Result<PathBuf> {
+        let wasm_bytes = std::fs::read(wasm_path)
+            .with_context(|| format!("failed to read input file {}", wasm_path.display()))?;
+
+        let cache_file = self.entry_path(&wasm_bytes);
+
+        // an entry that can't be deserialized anyway is recompiled by `load_module`
+        if cache_file.exists() {
+            self.hits.fetch_add(1, Ordering::Relaxed);
+            info!("cache hit for {}", wasm_path.display());
+            return Ok(cache_file);
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+        info!("cache miss for {}, compiling", wasm_path.display());
+
+        write_entry(
+            &cache_file,
+            &environment.engine.precompile_module(&wasm_bytes)?,
+        )?;
+
+        Ok(cache_file)
+    }
+
+    /// Remove all entries that are not in `used`, returns the number of removed entries
+    pub fn gc(&self, used: &HashSet<PathBuf>) -> Result<usize> {
+        let mut removed = 0;
+
+        for entry in std::fs::read_dir(&self.dir)? {
+            let path = entry?.path();
+
+            let is_entry = path
+                .extension()
+                .map_or(false, |ext| ext == CACHE_EXTENSION || ext == "tmp");
+
+            if !path.is_file() || !is_entry || used.contains(&path) {
+                continue;
+            }
+
+            debug!("removing unused cache entry {}", path.display());
+            std::fs::remove_file(&path)?;
+            removed += 1;
+        }
+
+        Ok(removed)
+    }
+
+    pub fn hits(&self) -> u64 {
+        self.hits.load(Ordering::Relaxed)
+    }
+
+    pub fn misses(&self) -> u64 {
+        self.misses.load(Ordering::Relaxed)
+    }
+}
+
+fn write_entry(cache_file: &Path, serialized: &[u8]) -> Result<()> {
+    static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    // write to a temporary file first, so a concurrent reader never sees a partial entry.
+    // Every writer gets its own file, concurrent writers of the same entry would otherwise race.
+    let tmp_file = cache_file.with_extension(format!(
+        "{}.{}.tmp",
+        std::process::id(),
+        TMP_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    std::fs::write(&tmp_file, serialized)?;
+    std::fs::rename(&tmp_file, cache_file)?;
+
+    Ok(())
+}
+
+/// Load a precompiled module from its cache entry, the entry is compiled again from `wasm_path`
+/// if it can't be deserialized (e.g. it was corrupted)
+pub(crate) fn load_module(
+    environment: &Environment,
+    cache_file: &Path,
+    wasm_path: &Path,
+) -> Result<Module> {
+    // cache entries are only written by the cache itself
+    let err = match unsafe { Module::deserialize_file(&environment.engine, cache_file) } {
+        Ok(module) => return Ok(module),
+        Err(err) => err,
+    };
+    warn!(
+        "cache entry {} can not be loaded, recompiling: {}",
+        cache_file.display(),
+        err
+    );
+
+    let wasm_bytes = std::fs::read(wasm_path)
+        .with_context(|| format!("failed to read input file {}", wasm_path.display()))?;
+    let module = Module::new(&environment.engine, &wasm_bytes)?;
+    write_entry(cache_file, &module.serialize()?)?;
+
+    Ok(module)
+}
@@ -0,0 +1,91 @@
+use crate::config::{ClientConfig, RuntimeConfig, DEFAULT_CLUSTER};
+use crate::kube_client::{self, ClientLimits, ClientMode, KubeClientService};
+use crate::runtime::http_engine::watch_multiplexer::WatchMultiplexer;
+use anyhow::{Context, Result};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The clients of a cluster, shared by the modules that manage it
+pub(crate) struct Cluster {
+    pub(crate) name: String,
+    pub(crate) kubeconfig: kube::Config,
+    pub(crate) service: KubeClientService,
+    /// The rate limits of the API server of the cluster
+    pub(crate) limits: ClientLimits,
+    pub(crate) watch_multiplexer: Arc<WatchMultiplexer>,
+}
+
+/// The clusters modules can manage, by name
+pub struct ClusterRegistry {
+    clusters: HashMap<String, Cluster>,
+    client_config: ClientConfig,
+    mode: ClientMode,
+}
+
+impl ClusterRegistry {
+    /// Create the clients of every cluster, this needs a tokio runtime
+    pub async fn new(
+        kubeconfigs: Vec<(String, kube::Config)>,
+        client_config: &ClientConfig,
+        runtime_config: &RuntimeConfig,
+        mode: ClientMode,
+    ) -> Result<Self> {
+        let mut registry = Self {
+            clusters: HashMap::new(),
+            client_config: client_config.clone(),
+            mode,
+        };
+
+        for (name, kubeconfig) in kubeconfigs {
+            let service = registry
+                .client_service(&name, kubeconfig.clone())
+                .await
+                .with_context(|| format!("could not setup the kube client of cluster {}", name))?;
+            let limits = ClientLimits::new(client_config);
+            let watch_multiplexer = Arc::new(WatchMultiplexer::new(
+                kubeconfig.cluster_url.clone(),
+                runtime_config.watch_history_size,
+            ));
+
+            registry.clusters.insert(
+                name.clone(),
+                Cluster {
+                    name,
+                    kubeconfig,
+                    service,
+                    limits,
+                    watch_multiplexer,
+                },
+            );
+        }
+
+        Ok(registry)
+    }
+
+    /// The cluster with the given name, or the default cluster
+    pub(crate) fn get(&self, name: Option<&str>) -> Result<&Cluster> {
+        let name = name.unwrap_or(DEFAULT_CLUSTER);
+        self.clusters
+            .get(name)
+            .with_context(|| format!("unknown cluster {}", name))
+    }
+
+    /// A client of a cluster that is recorded or replayed, depending on the mode of the host
+    pub(crate) async fn client_service(
+        &self,
+        cluster: &str,
+        kubeconfig: kube::Config,
+    ) -> Result<KubeClientService> {
+        Ok(match &self.mode {
+            ClientMode::Live => {
+                kube_client::create_client_service(kubeconfig, &self.client_config).await?
+            }
+            ClientMode::Record(recorder) => kube_client::record(
+                kube_client::create_client_service(kubeconfig, &self.client_config).await?,
+                recorder.clone(),
+                cluster,
+            ),
+            ClientMode::Replay(recording) => kube_client::replay(recording.clone(), cluster),
+        })
+    }
+}
@@ -1,3 +1,5 @@
+use crate::abi::receive_buffer::ReceiveBuffer;
+use crate::abi::version::AbiVersion;
 use crate::modules::OpsRunner;
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
@@ -8,18 +10,27 @@ pub struct ControllerCtx {
     pub wasi_ctx: WasiCtx,
     #[allow(dead_code)]
     pub async_client_id: u64,
+    pub abi_version: AbiVersion,
     pub async_request_id_counter: Arc<AtomicU64>,
     pub ops_runner: Arc<Mutex<OpsRunner>>,
+    pub receive_buffer: Option<ReceiveBuffer>,
 }
 
 impl ControllerCtx {
-    pub fn new(wasi_ctx: WasiCtx, async_client_id: u64, ops_runner: Arc<Mutex<OpsRunner>>) -> Self {
+    pub fn new(
+        wasi_ctx: WasiCtx,
+        async_client_id: u64,
+        abi_version: AbiVersion,
+        ops_runner: Arc<Mutex<OpsRunner>>,
+    ) -> Self {
         let async_request_id_counter = Arc::new(AtomicU64::new(0));
         ControllerCtx {
             wasi_ctx,
             async_client_id,
+            abi_version,
             async_request_id_counter,
             ops_runner,
+            receive_buffer: None,
         }
     }
 }
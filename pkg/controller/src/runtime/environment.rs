@@ -1,15 +1,25 @@
 use crate::abi::register_imports;
+use crate::abi::version;
+use crate::config::{AllocationStrategy, RuntimeConfig};
 use crate::kube_client::KubeClientService;
+use crate::logging::{GuestOutput, GuestStream};
+use crate::metrics::Metrics;
 use crate::modules::ControllerModule;
 use crate::modules::ControllerModuleMetadata;
 use crate::modules::OpsRunner;
+use crate::modules::RunnerOptions;
 use crate::modules::WasmRuntime;
 use crate::runtime::controller_ctx::ControllerCtx;
+use crate::runtime::http_engine::outbound::OutboundClient;
+use crate::runtime::http_engine::watch_multiplexer::WatchMultiplexer;
 use anyhow::Error;
 use anyhow::{Context, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 use std::sync::Mutex;
 use tokio::sync::Semaphore as AsyncSemaphore;
+use tracing::debug;
 use wasmtime::{Config, Engine, InstanceAllocationStrategy, Linker, OptLevel};
 use wasmtime_wasi::WasiCtxBuilder;
 
@@ -17,10 +27,13 @@ use wasmtime_wasi::WasiCtxBuilder;
 pub struct Environment {
     pub(crate) engine: Engine,
     pub(crate) linker: Arc<Linker<ControllerCtx>>,
+    /// Identifies the engine configuration for which modules are compiled
+    pub(crate) fingerprint: String,
+    pub(crate) swapping: bool,
 }
 
 impl Environment {
-    pub fn new() -> Result<Self, Error> {
+    pub fn new(runtime_config: &RuntimeConfig) -> Result<Self, Error> {
         let mut config = Config::new();
         config.generate_address_map(false);
         // TODO: memory_init_cow is default true in newer versions of wasm time
@@ -35,11 +48,11 @@ impl Environment {
 
         println!("instance limits are {:?}", instance_limits);
 
-        if *super::COMPILE_WITH_UNINSTANTIATE {
+        if runtime_config.allocation == AllocationStrategy::Pooling {
             config.allocation_strategy(InstanceAllocationStrategy::Pooling {
                 strategy: wasmtime::PoolingAllocationStrategy::ReuseAffinity,
                 instance_limits: wasmtime::InstanceLimits {
-                    count: *super::POOL_SIZE,
+                    count: runtime_config.pool_size(),
                     ..instance_limits
                 },
             });
@@ -47,6 +60,18 @@ impl Environment {
 
         let engine = Engine::new(&config)?;
 
+        // wasmtime hashes its version and every setting that changes the compiled code
+        let mut compatibility = DefaultHasher::new();
+        engine
+            .precompile_compatibility_hash()
+            .hash(&mut compatibility);
+        let compilation_settings = format!(
+            "controller={} wasmtime={:016x}",
+            env!("CARGO_PKG_VERSION"),
+            compatibility.finish()
+        );
+        let fingerprint = blake3::hash(compilation_settings.as_bytes()).to_hex()[..16].to_string();
+
         let mut linker = Linker::new(&engine);
         wasmtime_wasi::add_to_linker(&mut linker, |cx: &mut ControllerCtx| &mut cx.wasi_ctx)?;
 
@@ -55,27 +80,11 @@ impl Environment {
         Ok(Self {
             engine,
             linker: Arc::new(linker),
+            fingerprint,
+            swapping: runtime_config.swapping,
         })
     }
 
-    pub async fn cache_precompile(
-        &self,
-        wasm_path: std::path::PathBuf,
-        cache_path: std::path::PathBuf,
-    ) -> anyhow::Result<std::path::PathBuf> {
-        let wasm_bytes = std::fs::read(&wasm_path).with_context(|| "failed to read input file")?;
-
-        let cache_key = blake3::hash(&wasm_bytes).to_hex().to_string();
-
-        let cache_file = cache_path.join(cache_key).with_extension("wasm");
-
-        if !cache_file.exists() {
-            std::fs::write(&cache_file, self.engine.precompile_module(&wasm_bytes)?)?;
-        }
-
-        Ok(cache_file)
-    }
-
     pub fn new_controller_module(
         &self,
         meta: ControllerModuleMetadata,
@@ -85,11 +94,50 @@ impl Environment {
         async_active_client_counter: Arc<AsyncSemaphore>,
         cluster_url: http::Uri,
         kube_client_service: KubeClientService,
+        watch_multiplexer: Option<Arc<WatchMultiplexer>>,
+        metrics: &Metrics,
     ) -> anyhow::Result<ControllerModule> {
+        let wasm_bytes = std::fs::read(&meta.wasm)
+            .with_context(|| format!("failed to read {}", meta.wasm.display()))?;
+        let abi_version = version::negotiate(&wasm_bytes)
+            .with_context(|| format!("module {} can not be loaded", meta.name))?;
+        drop(wasm_bytes);
+        debug!("module {} uses ABI {}", meta.name, abi_version);
+
+        let outbound_client = match &meta.outbound_http {
+            Some(config) => Some(Arc::new(OutboundClient::new(config).with_context(
+                || format!("invalid outbound HTTP config of module {}", meta.name),
+            )?)),
+            None => None,
+        };
+
+        let impersonation = match &meta.identity {
+            Some(identity) => {
+                identity
+                    .validate()
+                    .with_context(|| format!("invalid identity of module {}", meta.name))?;
+                Some(identity.impersonation_headers()?).filter(|headers| !headers.is_empty())
+            }
+            None => None,
+        };
+
+        if let Some(policy) = &meta.policy {
+            policy
+                .validate()
+                .with_context(|| format!("invalid policy of module {}", meta.name))?;
+        }
+
         let ops_runner = Arc::new(Mutex::new(OpsRunner::new(
             meta.name.clone(),
             cluster_url,
-            kube_client_service,
+            RunnerOptions {
+                service: kube_client_service,
+                watch_multiplexer,
+                outbound_client,
+                impersonation,
+                policy: meta.policy.clone(),
+            },
+            metrics,
         )));
 
         let envs = meta
@@ -99,23 +147,28 @@ impl Environment {
             .collect::<Vec<(String, String)>>();
 
         let wasi_ctx = WasiCtxBuilder::new()
-            .inherit_stdout()
-            .inherit_stderr()
+            .stdout(GuestOutput::new(meta.name.clone(), GuestStream::Stdout).into_pipe())
+            .stderr(GuestOutput::new(meta.name.clone(), GuestStream::Stderr).into_pipe())
             .envs(envs.as_ref())?
             .args(meta.args.as_ref())?
             .build();
 
-        let controller_ctx = ControllerCtx::new(wasi_ctx, async_client_id, ops_runner.clone());
+        let controller_ctx =
+            ControllerCtx::new(wasi_ctx, async_client_id, abi_version, ops_runner.clone());
 
         Ok(ControllerModule::new(
             WasmRuntime::new(
                 controller_ctx,
+                abi_version,
                 wasm_path,
+                meta.wasm.clone(),
                 swap_path,
                 self.clone(),
                 async_active_client_counter,
             ),
             ops_runner,
+            self.swapping,
+            meta.event_buffer.as_ref(),
         ))
     }
 }
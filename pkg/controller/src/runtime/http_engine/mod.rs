@@ -1,4 +1,9 @@
 pub mod http_data;
+pub mod outbound;
+pub mod policy;
 pub mod request_executor;
+pub mod watch_event;
+pub mod watch_filter;
+pub mod watch_multiplexer;
 
 pub(crate) use http_data::*;
@@ -0,0 +1,193 @@
+use super::http_data::HttpResponseMeta;
+use crate::modules::OutboundHttpConfig;
+use anyhow::{Context, Result};
+use http::HeaderMap;
+use hyper::client::HttpConnector;
+use hyper::Body;
+use hyper_rustls::{HttpsConnector, HttpsConnectorBuilder};
+use hyper_timeout::TimeoutConnector;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Destination a module is allowed to call: `<scheme>://<host>[:<port>]`,
+/// where the host may start with `*.` to allow all its subdomains
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct AllowedOrigin {
+    scheme: String,
+    host: String,
+    port: u16,
+}
+
+fn default_port(scheme: &str) -> Option<u16> {
+    match scheme {
+        "http" => Some(80),
+        "https" => Some(443),
+        _ => None,
+    }
+}
+
+impl FromStr for AllowedOrigin {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // the uri parser doesn't accept wildcards, so parse it with a placeholder label
+        let (wildcard, uri) = match s.find("://*.") {
+            Some(pos) => (true, format!("{}://wildcard.{}", &s[..pos], &s[pos + 5..])),
+            None => (false, s.to_string()),
+        };
+
+        let uri = http::Uri::from_str(&uri)
+            .with_context(|| format!("invalid outbound origin '{}'", s))?;
+
+        if uri.path_and_query().map_or(false, |p| p.as_str() != "/") {
+            anyhow::bail!("outbound origin '{}' can not contain a path", s);
+        }
+
+        let scheme = uri.scheme_str().unwrap_or_default().to_ascii_lowercase();
+        let port = uri
+            .port_u16()
+            .or_else(|| default_port(&scheme))
+            .ok_or_else(|| anyhow::anyhow!("outbound origin '{}' must use http or https", s))?;
+
+        let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+        let host = match wildcard {
+            true => host.replacen("wildcard.", "*.", 1),
+            false => host,
+        };
+
+        Ok(Self { scheme, host, port })
+    }
+}
+
+impl AllowedOrigin {
+    fn matches(&self, uri: &http::Uri) -> bool {
+        let scheme = uri.scheme_str().unwrap_or_default().to_ascii_lowercase();
+        let host = uri.host().unwrap_or_default().to_ascii_lowercase();
+        let port = uri.port_u16().or_else(|| default_port(&scheme));
+
+        let host_matches = match self.host.strip_prefix("*.") {
+            Some(domain) => host.ends_with(&format!(".{}", domain)),
+            None => host == self.host,
+        };
+
+        scheme == self.scheme && host_matches && port == Some(self.port)
+    }
+}
+
+/// HTTP client for requests of a module to services outside of the cluster,
+/// restricted to the origins configured in its `outboundHttp` metadata
+pub struct OutboundClient {
+    allowed: Vec<AllowedOrigin>,
+    request_timeout: Duration,
+    client: hyper::Client<TimeoutConnector<HttpsConnector<HttpConnector>>, Body>,
+}
+
+impl OutboundClient {
+    pub fn new(config: &OutboundHttpConfig) -> Result<Self> {
+        let allowed = config
+            .allow
+            .iter()
+            .map(|origin| origin.parse())
+            .collect::<Result<Vec<AllowedOrigin>>>()?;
+
+        if config.https_only {
+            if let Some(origin) = allowed.iter().find(|origin| origin.scheme != "https") {
+                anyhow::bail!(
+                    "outbound origin {}://{} is not allowed when httpsOnly is set",
+                    origin.scheme,
+                    origin.host
+                );
+            }
+        }
+
+        let https = HttpsConnectorBuilder::new().with_native_roots();
+        let https = match config.https_only {
+            true => https.https_only().enable_http1().build(),
+            false => https.https_or_http().enable_http1().build(),
+        };
+
+        let mut connector = TimeoutConnector::new(https);
+        connector.set_connect_timeout(Some(Duration::from_millis(config.connect_timeout_ms)));
+
+        Ok(Self {
+            allowed,
+            request_timeout: Duration::from_millis(config.request_timeout_ms),
+            client: hyper::Client::builder().build(connector),
+        })
+    }
+
+    /// Check that the module may call the uri, returns the reason if it may not
+    pub fn check(&self, uri: &http::Uri) -> Result<(), String> {
+        if self.allowed.iter().any(|origin| origin.matches(uri)) {
+            Ok(())
+        } else {
+            Err(format!("{} is not in the outbound HTTP allowlist", uri))
+        }
+    }
+
+    /// Execute the request and read the complete body, within the request timeout
+    pub async fn execute(
+        &self,
+        request: http::Request<Vec<u8>>,
+    ) -> Result<(HttpResponseMeta, bytes::Bytes)> {
+        let uri = request.uri().clone();
+
+        let response = async {
+            let response = self.client.request(request.map(Body::from)).await?;
+
+            let status_code = response.status();
+            let mut headers = HeaderMap::with_capacity(response.headers().len());
+            for (k, v) in response.headers().iter() {
+                headers.append(k, v.clone());
+            }
+
+            let body = hyper::body::to_bytes(response.into_body()).await?;
+
+            Ok::<_, hyper::Error>((
+                HttpResponseMeta {
+                    status_code,
+                    headers,
+                },
+                body,
+            ))
+        };
+
+        tokio::time::timeout(self.request_timeout, response)
+            .await
+            .with_context(|| format!("request to {} timed out", uri))?
+            .with_context(|| format!("request to {} failed", uri))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uri(s: &str) -> http::Uri {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allowed_origin() {
+        let origin: AllowedOrigin = "https://hooks.example.com".parse().unwrap();
+        assert!(origin.matches(&uri("https://hooks.example.com/api?x=1")));
+        assert!(origin.matches(&uri("https://HOOKS.example.com:443/")));
+        assert!(!origin.matches(&uri("http://hooks.example.com/")));
+        assert!(!origin.matches(&uri("https://hooks.example.com:8443/")));
+        assert!(!origin.matches(&uri("https://evil.com/hooks.example.com")));
+    }
+
+    #[test]
+    fn test_wildcard_origin() {
+        let origin: AllowedOrigin = "https://*.example.com:8443".parse().unwrap();
+        assert!(origin.matches(&uri("https://a.b.example.com:8443/")));
+        assert!(!origin.matches(&uri("https://example.com:8443/")));
+        assert!(!origin.matches(&uri("https://badexample.com:8443/")));
+    }
+
+    #[test]
+    fn test_invalid_origin() {
+        assert!("https://example.com/path".parse::<AllowedOrigin>().is_err());
+        assert!("ftp://example.com".parse::<AllowedOrigin>().is_err());
+    }
+}
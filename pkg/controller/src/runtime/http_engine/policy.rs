@@ -0,0 +1,305 @@
+use super::http_data::HttpResponseMeta;
+use crate::modules::{ModulePolicy, PolicyRule};
+use http::{HeaderMap, HeaderValue, Method, StatusCode};
+
+/// Kubernetes API request attributes, as used by the API server for authorization
+#[derive(Debug, PartialEq, Eq)]
+struct ResourceRequest<'a> {
+    verb: &'static str,
+    api_group: &'a str,
+    /// The resource, followed by `/<subresource>` for subresources (e.g. `pods/log`)
+    resource: String,
+    namespace: Option<&'a str>,
+}
+
+/// Parse the attributes of a request, `None` for non-resource paths
+fn parse_request<'a>(method: &Method, uri: &'a http::Uri) -> Option<ResourceRequest<'a>> {
+    let segments = uri
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .collect::<Vec<&str>>();
+
+    let (api_group, rest) = match segments.as_slice() {
+        ["api", _version, rest @ ..] => ("", rest),
+        ["apis", group, _version, rest @ ..] => (*group, rest),
+        _ => return None,
+    };
+
+    // the deprecated `/watch/` prefix, which the API server still serves
+    let (watch_prefix, rest) = match rest {
+        ["watch", rest @ ..] => (true, rest),
+        _ => (false, rest),
+    };
+
+    let (namespace, rest) = match rest {
+        ["namespaces", namespace, rest @ ..] if !rest.is_empty() => (Some(*namespace), rest),
+        _ => (None, rest),
+    };
+
+    let (resource, name, subresource) = match rest {
+        [resource] => (*resource, None, None),
+        [resource, name] => (*resource, Some(*name), None),
+        [resource, name, subresource] => (*resource, Some(*name), Some(*subresource)),
+        _ => return None,
+    };
+
+    // `namespaces/<name>` is the namespace itself
+    let namespace = match (resource, name) {
+        ("namespaces", Some(name)) => Some(name),
+        _ => namespace,
+    };
+
+    let watch = query_flag(uri, "watch");
+
+    let verb = match (method, name.is_some()) {
+        _ if watch_prefix => "watch",
+        (&Method::GET, true) | (&Method::HEAD, true) => "get",
+        (&Method::GET, false) | (&Method::HEAD, false) if watch => "watch",
+        (&Method::GET, false) | (&Method::HEAD, false) => "list",
+        (&Method::POST, _) => "create",
+        (&Method::PUT, _) => "update",
+        (&Method::PATCH, _) => "patch",
+        (&Method::DELETE, true) => "delete",
+        (&Method::DELETE, false) => "deletecollection",
+        _ => "unknown",
+    };
+
+    Some(ResourceRequest {
+        verb,
+        api_group,
+        resource: match subresource {
+            Some(subresource) => format!("{}/{}", resource, subresource),
+            None => resource.to_string(),
+        },
+        namespace,
+    })
+}
+
+/// Whether a boolean query parameter is set. The first occurrence counts and its value is parsed
+/// like Go's `strconv.ParseBool`, as the API server does
+pub(crate) fn query_flag(uri: &http::Uri, name: &str) -> bool {
+    uri.query().map_or(false, |query| {
+        query
+            .split('&')
+            .find_map(|param| param.strip_prefix(name)?.strip_prefix('='))
+            .map_or(false, |value| {
+                matches!(value, "1" | "t" | "T" | "true" | "TRUE" | "True")
+            })
+    })
+}
+
+/// Whether the API server serves a request as a watch, by a `watch` parameter or the `/watch/` prefix
+pub(crate) fn is_watch(method: &Method, uri: &http::Uri) -> bool {
+    parse_request(method, uri).map_or(false, |request| request.verb == "watch")
+}
+
+/// Discovery and version endpoints that every client needs
+fn is_discovery(method: &Method, uri: &http::Uri) -> bool {
+    let segments = uri
+        .path()
+        .trim_matches('/')
+        .split('/')
+        .collect::<Vec<&str>>();
+
+    method == Method::GET
+        && matches!(
+            segments.as_slice(),
+            ["api"] | ["api", _] | ["apis"] | ["apis", _] | ["apis", _, _] | ["version"]
+        )
+}
+
+fn matches(values: &[String], value: &str) -> bool {
+    values.iter().any(|v| v == "*" || v == value)
+}
+
+impl PolicyRule {
+    fn allows(&self, request: &ResourceRequest) -> bool {
+        let namespace_allowed = self.namespaces.is_empty()
+            || self.namespaces.iter().any(|namespace| namespace == "*")
+            || request
+                .namespace
+                .map_or(false, |namespace| matches(&self.namespaces, namespace));
+
+        matches(&self.api_groups, request.api_group)
+            && matches(&self.resources, &request.resource)
+            && matches(&self.verbs, request.verb)
+            && namespace_allowed
+    }
+}
+
+impl ModulePolicy {
+    /// Check the request against the rules, returns the reason if it is not allowed
+    pub fn check(&self, method: &Method, uri: &http::Uri) -> Result<(), String> {
+        if is_discovery(method, uri) {
+            return Ok(());
+        }
+
+        let request = match parse_request(method, uri) {
+            Some(request) => request,
+            None => return Err(format!("path {} is not allowed", uri.path())),
+        };
+
+        if self.rules.iter().any(|rule| rule.allows(&request)) {
+            Ok(())
+        } else {
+            Err(format!(
+                "{} on {} in group \"{}\"{} is not allowed",
+                request.verb,
+                request.resource,
+                request.api_group,
+                request
+                    .namespace
+                    .map(|namespace| format!(" in namespace {}", namespace))
+                    .unwrap_or_default()
+            ))
+        }
+    }
+}
+
+/// A `403 Forbidden` response with a Kubernetes `Status` body, as the API server would return it
+pub fn forbidden_response(reason: &str) -> (HttpResponseMeta, bytes::Bytes) {
+    let status = serde_json::json!({
+        "kind": "Status",
+        "apiVersion": "v1",
+        "metadata": {},
+        "status": "Failure",
+        "message": format!("denied by the module policy: {}", reason),
+        "reason": "Forbidden",
+        "code": 403,
+    });
+
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        http::header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+
+    (
+        HttpResponseMeta {
+            status_code: StatusCode::FORBIDDEN,
+            headers,
+        },
+        bytes::Bytes::from(status.to_string()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(
+        api_groups: &[&str],
+        resources: &[&str],
+        verbs: &[&str],
+        namespaces: &[&str],
+    ) -> PolicyRule {
+        let strings = |values: &[&str]| values.iter().map(|v| v.to_string()).collect();
+        PolicyRule {
+            api_groups: strings(api_groups),
+            resources: strings(resources),
+            verbs: strings(verbs),
+            namespaces: strings(namespaces),
+        }
+    }
+
+    fn check(policy: &ModulePolicy, method: Method, uri: &str) -> bool {
+        policy.check(&method, &uri.parse().unwrap()).is_ok()
+    }
+
+    #[test]
+    fn test_parse_request() {
+        let uri = "/apis/apps/v1/namespaces/default/deployments/web/scale"
+            .parse()
+            .unwrap();
+        assert_eq!(
+            parse_request(&Method::PUT, &uri),
+            Some(ResourceRequest {
+                verb: "update",
+                api_group: "apps",
+                resource: "deployments/scale".to_string(),
+                namespace: Some("default"),
+            })
+        );
+
+        let uri = "/api/v1/pods?watch=true&resourceVersion=1".parse().unwrap();
+        assert_eq!(parse_request(&Method::GET, &uri).unwrap().verb, "watch");
+        assert_eq!(parse_request(&Method::GET, &uri).unwrap().namespace, None);
+
+        for value in &["1", "t", "T", "true", "TRUE", "True"] {
+            let uri = format!("/api/v1/pods?watch={}", value).parse().unwrap();
+            assert_eq!(parse_request(&Method::GET, &uri).unwrap().verb, "watch");
+        }
+        let uri = "/api/v1/pods?watch=false&watch=true".parse().unwrap();
+        assert_eq!(parse_request(&Method::GET, &uri).unwrap().verb, "list");
+
+        let uri = "/api/v1/watch/namespaces/default/pods/a".parse().unwrap();
+        assert_eq!(
+            parse_request(&Method::GET, &uri),
+            Some(ResourceRequest {
+                verb: "watch",
+                api_group: "",
+                resource: "pods".to_string(),
+                namespace: Some("default"),
+            })
+        );
+
+        let uri = "/api/v1/namespaces/kube-system".parse().unwrap();
+        let request = parse_request(&Method::DELETE, &uri).unwrap();
+        assert_eq!(request.resource, "namespaces");
+        assert_eq!(request.namespace, Some("kube-system"));
+    }
+
+    #[test]
+    fn test_policy() {
+        let policy = ModulePolicy {
+            rules: vec![
+                rule(&[""], &["pods"], &["get", "list", "watch"], &["default"]),
+                rule(&["amurant.io"], &["*"], &["*"], &[]),
+                rule(&[""], &["secrets"], &["list"], &[]),
+            ],
+        };
+
+        assert!(check(
+            &policy,
+            Method::GET,
+            "/api/v1/namespaces/default/pods"
+        ));
+        assert!(check(
+            &policy,
+            Method::GET,
+            "/api/v1/namespaces/default/pods/a"
+        ));
+        assert!(!check(
+            &policy,
+            Method::DELETE,
+            "/api/v1/namespaces/default/pods/a"
+        ));
+        assert!(!check(
+            &policy,
+            Method::GET,
+            "/api/v1/namespaces/other/pods"
+        ));
+        assert!(!check(&policy, Method::GET, "/api/v1/pods"));
+        assert!(check(&policy, Method::GET, "/api/v1/secrets"));
+        assert!(!check(&policy, Method::GET, "/api/v1/watch/secrets"));
+        assert!(!check(&policy, Method::GET, "/api/v1/secrets?watch=True"));
+        assert!(check(
+            &policy,
+            Method::GET,
+            "/api/v1/watch/namespaces/default/pods"
+        ));
+        assert!(!check(
+            &policy,
+            Method::GET,
+            "/api/v1/namespaces/default/pods/a/log"
+        ));
+        assert!(check(
+            &policy,
+            Method::POST,
+            "/apis/amurant.io/v1/namespaces/x/testresources"
+        ));
+        assert!(check(&policy, Method::GET, "/apis/amurant.io/v1"));
+        assert!(!check(&policy, Method::GET, "/metrics"));
+    }
+}
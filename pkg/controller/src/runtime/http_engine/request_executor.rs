@@ -1,9 +1,10 @@
 use http::HeaderMap;
+use std::sync::Arc;
 use tower::ServiceExt;
 use tower_service::Service;
 
 use super::http_data::HttpResponseMeta;
-use crate::kube_client::KubeClientService;
+use crate::kube_client::{KubeClientService, RetryBody};
 
 /// An internal url joiner to deal with the two different interfaces
 ///
@@ -24,15 +25,39 @@ pub(crate) async fn start_request_executor(
     mut request: http::Request<Vec<u8>>,
     cluster_url: http::Uri,
     mut service: KubeClientService,
+    impersonation: Option<Arc<HeaderMap>>,
 ) -> anyhow::Result<(HttpResponseMeta, hyper::Body)> {
     // Patch the request URI
     *request.uri_mut() = generate_url(&cluster_url, request.uri().path_and_query().unwrap());
 
+    // the module can not choose its own identity, it would borrow the `impersonate` permission of the host
+    let headers = request.headers_mut();
+    let guest_impersonation = headers
+        .keys()
+        .filter(|name| name.as_str().starts_with("impersonate-"))
+        .cloned()
+        .collect::<Vec<_>>();
+    for name in guest_impersonation {
+        headers.remove(name);
+    }
+
+    if let Some(impersonation) = impersonation {
+        for (name, value) in impersonation.iter() {
+            headers.append(name, value.clone());
+        }
+    }
+
+    // keep a copy of the body, so the request can be retried
+    let (parts, body) = request.into_parts();
+    let body = bytes::Bytes::from(body);
+    let mut request = http::Request::from_parts(parts, hyper::Body::from(body.clone()));
+    request.extensions_mut().insert(RetryBody(body));
+
     let response = service
         .ready()
         .await
         .map_err(|e| anyhow::anyhow!("{:?}", e))?
-        .call(request.map(hyper::Body::from))
+        .call(request)
         .await
         .map_err(|e| anyhow::anyhow!("{:?}", e))?;
 
@@ -0,0 +1,29 @@
+use bytes::Bytes;
+use serde_json::Value;
+
+/// Identifies the object of a watch event, by its uid or else by its namespace and name
+pub fn object_key(object: &Value) -> Option<String> {
+    let metadata = &object["metadata"];
+    match (metadata["uid"].as_str(), metadata["name"].as_str()) {
+        (Some(uid), _) => Some(uid.to_string()),
+        (None, Some(name)) => Some(format!(
+            "{}/{}",
+            metadata["namespace"].as_str().unwrap_or_default(),
+            name
+        )),
+        (None, None) => None,
+    }
+}
+
+/// The event with another type, e.g. `DELETED` for an object that stopped matching a filter
+pub fn with_event_type(line: &[u8], event_type: &str) -> Bytes {
+    match serde_json::from_slice::<Value>(line) {
+        Ok(mut event) => {
+            event["type"] = Value::from(event_type);
+            let mut line = serde_json::to_vec(&event).expect("serializing a json value");
+            line.push(b'\n');
+            Bytes::from(line)
+        }
+        Err(_) => Bytes::copy_from_slice(line),
+    }
+}
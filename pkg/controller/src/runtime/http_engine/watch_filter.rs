@@ -0,0 +1,223 @@
+use super::watch_event::{object_key, with_event_type};
+use bytes::{Bytes, BytesMut};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::{HashMap, HashSet};
+
+/// Filter a module registers for the events of a watch, same layout as `kube_runtime_abi::WatchFilter`
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct WatchFilter {
+    labels: Vec<(String, String)>,
+    fields: Vec<(String, String)>,
+    generation_changed: bool,
+}
+
+fn field<'a>(object: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(object, |value, segment| value.get(segment))
+}
+
+fn field_matches(object: &Value, path: &str, expected: &str) -> bool {
+    match field(object, path) {
+        None | Some(Value::Null) => false,
+        Some(Value::String(value)) => value == expected,
+        Some(value) => serde_json::to_string(value).map_or(false, |value| value == expected),
+    }
+}
+
+enum Filtered {
+    Passed,
+    Dropped,
+    /// The object no longer matches, for the module it is deleted
+    Left,
+}
+
+/// Applies a `WatchFilter` to the chunks of a watch response
+pub struct StreamFilter {
+    filter: WatchFilter,
+    /// Incomplete last line of the received chunks
+    partial: BytesMut,
+    /// Last seen generation of every object, for `generation_changed`
+    generations: HashMap<String, i64>,
+    /// Objects whose last event matched the labels and fields, the module knows them
+    selected: HashSet<String>,
+}
+
+impl StreamFilter {
+    pub fn new(filter: WatchFilter) -> Self {
+        Self {
+            filter,
+            partial: BytesMut::new(),
+            generations: HashMap::new(),
+            selected: HashSet::new(),
+        }
+    }
+
+    /// The complete events of the chunk that pass the filter, `None` if there are none
+    pub fn apply(&mut self, chunk: &[u8]) -> Option<Bytes> {
+        self.partial.extend_from_slice(chunk);
+
+        let mut passed = BytesMut::new();
+        while let Some(pos) = self.partial.iter().position(|b| *b == b'\n') {
+            let line = self.partial.split_to(pos + 1);
+            match self.filter_event(&line) {
+                Filtered::Passed => passed.extend_from_slice(&line),
+                Filtered::Left => passed.extend_from_slice(&with_event_type(&line, "DELETED")),
+                Filtered::Dropped => {}
+            }
+        }
+
+        Some(passed.freeze()).filter(|passed| !passed.is_empty())
+    }
+
+    /// The incomplete last line once the response ended
+    pub fn finish(&mut self) -> Option<Bytes> {
+        Some(self.partial.split().freeze()).filter(|rest| !rest.is_empty())
+    }
+
+    fn filter_event(&mut self, line: &[u8]) -> Filtered {
+        // the module has to handle what can't be understood here
+        let event = match serde_json::from_slice::<Value>(line) {
+            Ok(event) => event,
+            Err(_) => return Filtered::Passed,
+        };
+        let event_type = match event.get("type").and_then(Value::as_str) {
+            Some("BOOKMARK") | Some("ERROR") | None => return Filtered::Passed,
+            Some(event_type) => event_type,
+        };
+        let object = &event["object"];
+        let metadata = &object["metadata"];
+
+        let key = object_key(object);
+        let generation_changed = match &key {
+            Some(key) if event_type == "DELETED" => {
+                self.generations.remove(key);
+                true
+            }
+            Some(key) => match metadata["generation"].as_i64() {
+                Some(generation) => {
+                    self.generations.insert(key.clone(), generation) != Some(generation)
+                }
+                None => true,
+            },
+            None => true,
+        };
+
+        let labels_match = self.filter.labels.iter().all(|(key, value)| {
+            metadata["labels"].get(key).and_then(Value::as_str) == Some(value.as_str())
+        });
+        let fields_match = self
+            .filter
+            .fields
+            .iter()
+            .all(|(path, value)| field_matches(object, path, value));
+
+        let matches = labels_match && fields_match;
+
+        let was_selected = match &key {
+            Some(key) if matches && event_type != "DELETED" => !self.selected.insert(key.clone()),
+            Some(key) => self.selected.remove(key),
+            None => false,
+        };
+
+        match (matches, event_type) {
+            (true, "MODIFIED")
+                if self.filter.generation_changed && !generation_changed && was_selected =>
+            {
+                Filtered::Dropped
+            }
+            (true, _) => Filtered::Passed,
+            (false, "DELETED") if was_selected => Filtered::Passed,
+            (false, "MODIFIED") if was_selected => Filtered::Left,
+            (false, _) => Filtered::Dropped,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(event_type: &str, generation: i64, app: &str, phase: &str) -> String {
+        format!(
+            "{{\"type\":\"{}\",\"object\":{{\"metadata\":{{\"uid\":\"a\",\"generation\":{},\"labels\":{{\"app\":\"{}\"}}}},\"status\":{{\"phase\":\"{}\"}}}}}}\n",
+            event_type, generation, app, phase
+        )
+    }
+
+    #[test]
+    fn test_generation_changed() {
+        let mut filter = StreamFilter::new(WatchFilter {
+            generation_changed: true,
+            ..Default::default()
+        });
+
+        assert!(filter
+            .apply(event("ADDED", 1, "web", "Pending").as_bytes())
+            .is_some());
+        assert!(filter
+            .apply(event("MODIFIED", 1, "web", "Running").as_bytes())
+            .is_none());
+        assert!(filter
+            .apply(event("MODIFIED", 2, "web", "Running").as_bytes())
+            .is_some());
+        assert!(filter
+            .apply(event("DELETED", 2, "web", "Running").as_bytes())
+            .is_some());
+    }
+
+    #[test]
+    fn test_object_stops_matching() {
+        let mut filter = StreamFilter::new(WatchFilter {
+            labels: vec![("app".to_string(), "web".to_string())],
+            ..Default::default()
+        });
+
+        let event_type = |events: Option<Bytes>| {
+            let event: Value = serde_json::from_slice(&events.unwrap()).unwrap();
+            event["type"].as_str().unwrap().to_string()
+        };
+
+        assert_eq!(
+            event_type(filter.apply(event("ADDED", 1, "web", "Pending").as_bytes())),
+            "ADDED"
+        );
+        // the label changed, the module has to forget the object
+        assert_eq!(
+            event_type(filter.apply(event("MODIFIED", 1, "db", "Pending").as_bytes())),
+            "DELETED"
+        );
+        assert!(filter
+            .apply(event("MODIFIED", 1, "db", "Running").as_bytes())
+            .is_none());
+        assert!(filter
+            .apply(event("DELETED", 1, "db", "Running").as_bytes())
+            .is_none());
+    }
+
+    #[test]
+    fn test_labels_and_fields() {
+        let mut filter = StreamFilter::new(WatchFilter {
+            labels: vec![("app".to_string(), "web".to_string())],
+            fields: vec![("status.phase".to_string(), "Running".to_string())],
+            generation_changed: false,
+        });
+
+        let passing = event("MODIFIED", 1, "web", "Running");
+        let leaving = event("MODIFIED", 1, "web", "Pending");
+        let chunks = format!(
+            "{}{}{}",
+            event("MODIFIED", 1, "db", "Running"),
+            passing,
+            leaving
+        );
+
+        // events split over chunks are only evaluated once complete
+        let (first, second) = chunks.split_at(20);
+        assert_eq!(filter.apply(first.as_bytes()), None);
+        let mut passed = BytesMut::from(passing.as_bytes());
+        passed.extend_from_slice(&with_event_type(leaving.as_bytes(), "DELETED"));
+        assert_eq!(filter.apply(second.as_bytes()), Some(passed.freeze()));
+        assert_eq!(filter.finish(), None);
+    }
+}
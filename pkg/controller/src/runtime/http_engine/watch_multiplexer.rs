@@ -0,0 +1,449 @@
+use super::http_data::HttpResponseMeta;
+use super::policy;
+use super::request_executor::start_request_executor;
+use crate::kube_client::KubeClientService;
+use bytes::{Bytes, BytesMut};
+use futures::future::{AbortHandle, Abortable};
+use futures::stream::BoxStream;
+use futures::StreamExt;
+use http::HeaderMap;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex, Weak};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::debug;
+
+/// Body of a watch response, one watch event per chunk for shared watches
+pub type WatchBody = BoxStream<'static, anyhow::Result<Bytes>>;
+
+// these only change from where and how long a subscriber watches, not what it watches
+const IGNORED_PARAMS: [&str; 2] = ["resourceVersion", "timeoutSeconds"];
+
+#[derive(Clone)]
+enum WatchEvent {
+    Line(Bytes),
+    /// The upstream watch ended, with the error if it failed
+    End(Option<String>),
+}
+
+#[derive(Deserialize)]
+struct EventObject {
+    object: EventObjectMeta,
+}
+
+#[derive(Deserialize)]
+struct EventObjectMeta {
+    metadata: ObjectMeta,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ObjectMeta {
+    resource_version: Option<String>,
+}
+
+fn event_resource_version(line: &[u8]) -> Option<String> {
+    serde_json::from_slice::<EventObject>(line)
+        .ok()
+        .and_then(|event| event.object.metadata.resource_version)
+}
+
+/// Resource versions are opaque to clients, but the API server uses the increasing etcd revisions
+fn parse_resource_version(resource_version: &str) -> Option<u64> {
+    resource_version.parse().ok()
+}
+
+fn query_params(uri: &http::Uri) -> impl Iterator<Item = (&str, &str)> {
+    uri.query()
+        .unwrap_or_default()
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .map(|param| match param.find('=') {
+            Some(pos) => (&param[..pos], &param[pos + 1..]),
+            None => (param, ""),
+        })
+}
+
+fn resource_version(uri: &http::Uri) -> Option<&str> {
+    query_params(uri)
+        .find(|(name, _)| *name == "resourceVersion")
+        .map(|(_, value)| value)
+}
+
+/// Identifies the watches that return the same events: the same request, apart from the ignored
+/// parameters, made with the same identity
+fn watch_key(request: &http::Request<Vec<u8>>, impersonation: Option<&HeaderMap>) -> String {
+    let mut params = query_params(request.uri())
+        .filter(|(name, _)| !IGNORED_PARAMS.contains(name))
+        .map(|(name, value)| format!("{}={}", name, value))
+        .collect::<Vec<String>>();
+    params.sort();
+
+    let mut headers = request
+        .headers()
+        .iter()
+        .chain(impersonation.into_iter().flat_map(|headers| headers.iter()))
+        .map(|(name, value)| format!("{}: {}", name, String::from_utf8_lossy(value.as_bytes())))
+        .collect::<Vec<String>>();
+    headers.sort();
+
+    format!(
+        "{}?{}\n{}",
+        request.uri().path(),
+        params.join("&"),
+        headers.join("\n")
+    )
+}
+
+struct HistoryEntry {
+    resource_version: Option<u64>,
+    line: Bytes,
+}
+
+/// Events of a shared watch, so modules can join it from the resource version they are at
+struct History {
+    entries: VecDeque<HistoryEntry>,
+    /// The history has every event after this resource version, `None` if that is unknown
+    since_resource_version: Option<u64>,
+    ended: bool,
+    sender: broadcast::Sender<WatchEvent>,
+}
+
+struct WatchState {
+    key: String,
+    meta: HttpResponseMeta,
+    history_size: usize,
+    history: Mutex<History>,
+}
+
+impl WatchState {
+    fn publish(&self, line: Bytes) {
+        let mut history = self.history.lock().unwrap();
+
+        if history.entries.len() >= self.history_size {
+            if let Some(dropped) = history.entries.pop_front() {
+                history.since_resource_version = dropped.resource_version;
+            }
+        }
+        history.entries.push_back(HistoryEntry {
+            resource_version: event_resource_version(&line)
+                .as_deref()
+                .and_then(parse_resource_version),
+            line: line.clone(),
+        });
+
+        // only fails if there are no subscribers left, then the watch is aborted anyway
+        let _ = history.sender.send(WatchEvent::Line(line));
+    }
+
+    fn end(&self, error: Option<String>) {
+        let mut history = self.history.lock().unwrap();
+        history.ended = true;
+        let _ = history.sender.send(WatchEvent::End(error));
+    }
+}
+
+/// An upstream watch, aborted when its last subscriber is dropped
+struct SharedWatch {
+    state: Arc<WatchState>,
+    abort_handle: AbortHandle,
+}
+
+impl Drop for SharedWatch {
+    fn drop(&mut self) {
+        debug!(
+            "stopping shared watch {}",
+            self.state.key.lines().next().unwrap_or_default()
+        );
+        self.abort_handle.abort();
+    }
+}
+
+impl SharedWatch {
+    fn start(
+        key: String,
+        start_resource_version: Option<u64>,
+        meta: HttpResponseMeta,
+        body: hyper::Body,
+        history_size: usize,
+    ) -> (Arc<SharedWatch>, WatchBody) {
+        let (sender, receiver) = broadcast::channel(history_size);
+        let state = Arc::new(WatchState {
+            key,
+            meta,
+            history_size,
+            history: Mutex::new(History {
+                entries: VecDeque::with_capacity(history_size),
+                since_resource_version: start_resource_version,
+                ended: false,
+                sender,
+            }),
+        });
+
+        let (abort_handle, abort_registration) = AbortHandle::new_pair();
+        tokio::spawn(Abortable::new(
+            forward_events(state.clone(), body),
+            abort_registration,
+        ));
+
+        let shared = Arc::new(SharedWatch {
+            state,
+            abort_handle,
+        });
+        let body = subscription(shared.clone(), Vec::new(), receiver);
+
+        (shared, body)
+    }
+
+    /// Subscribe to the events after `resource_version`, if they are all still in the history and
+    /// the watch is not behind it
+    fn subscribe_from(self: &Arc<Self>, resource_version: &str) -> Option<WatchBody> {
+        let history = self.state.history.lock().unwrap();
+        if history.ended {
+            return None;
+        }
+
+        let requested = parse_resource_version(resource_version)?;
+        let since = history.since_resource_version?;
+        let latest = history
+            .entries
+            .iter()
+            .rev()
+            .find_map(|entry| entry.resource_version)
+            .unwrap_or(since);
+        if requested < since || requested > latest {
+            return None;
+        }
+
+        // events without a resource version stay with the events before them
+        let start = history
+            .entries
+            .iter()
+            .rposition(|entry| entry.resource_version.map_or(false, |rv| rv <= requested))
+            .map_or(0, |pos| pos + 1);
+
+        let replay = history
+            .entries
+            .iter()
+            .skip(start)
+            .map(|entry| entry.line.clone())
+            .collect();
+
+        // subscribing while holding the lock guarantees no event is missed or received twice
+        let receiver = history.sender.subscribe();
+
+        Some(subscription(self.clone(), replay, receiver))
+    }
+
+    fn meta(&self) -> HttpResponseMeta {
+        HttpResponseMeta {
+            status_code: self.state.meta.status_code,
+            headers: self.state.meta.headers.clone(),
+        }
+    }
+}
+
+/// Split the body of the upstream watch in events and publish them to the subscribers
+async fn forward_events(state: Arc<WatchState>, mut body: hyper::Body) {
+    let mut pending = BytesMut::new();
+
+    let error = loop {
+        match body.next().await {
+            Some(Ok(chunk)) => {
+                pending.extend_from_slice(&chunk);
+                while let Some(pos) = pending.iter().position(|b| *b == b'\n') {
+                    state.publish(pending.split_to(pos + 1).freeze());
+                }
+            }
+            Some(Err(err)) => break Some(err.to_string()),
+            None => break None,
+        }
+    };
+
+    if !pending.is_empty() {
+        state.publish(pending.freeze());
+    }
+
+    state.end(error);
+}
+
+fn subscription(
+    shared: Arc<SharedWatch>,
+    replay: Vec<Bytes>,
+    receiver: broadcast::Receiver<WatchEvent>,
+) -> WatchBody {
+    let live = futures::stream::unfold(Some((shared, receiver)), |state| async move {
+        let (shared, mut receiver) = state?;
+
+        match receiver.recv().await {
+            Ok(WatchEvent::Line(line)) => Some((Ok(line), Some((shared, receiver)))),
+            Ok(WatchEvent::End(None)) | Err(RecvError::Closed) => None,
+            Ok(WatchEvent::End(Some(err))) => Some((Err(anyhow::anyhow!(err)), None)),
+            // the module can restart the watch from the last event it received
+            Err(RecvError::Lagged(missed)) => Some((
+                Err(anyhow::anyhow!(
+                    "fell {} events behind the shared watch",
+                    missed
+                )),
+                None,
+            )),
+        }
+    });
+
+    futures::stream::iter(replay.into_iter().map(Ok))
+        .chain(live)
+        .boxed()
+}
+
+/// Shares a single upstream watch between all modules that make the same watch request
+pub struct WatchMultiplexer {
+    cluster_url: http::Uri,
+    history_size: usize,
+    watches: Mutex<HashMap<String, Weak<SharedWatch>>>,
+}
+
+impl WatchMultiplexer {
+    pub fn new(cluster_url: http::Uri, history_size: usize) -> Self {
+        Self {
+            cluster_url,
+            history_size,
+            watches: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Only watches from a specific resource version can be shared, without one the API server first
+    /// sends the current state of all objects
+    pub fn is_shareable(&self, request: &http::Request<Vec<u8>>) -> bool {
+        self.history_size > 0
+            && request.method() == http::Method::GET
+            && policy::is_watch(request.method(), request.uri())
+            && matches!(resource_version(request.uri()), Some(rv) if !rv.is_empty() && rv != "0")
+    }
+
+    /// Join a shared watch for the request if one can deliver all events after its resource version,
+    /// otherwise start a new upstream watch that later requests can join. The new watch is sent with the
+    /// `service` of the requesting module, so it counts against the rate limits of that module.
+    pub async fn watch(
+        &self,
+        request: http::Request<Vec<u8>>,
+        service: KubeClientService,
+        impersonation: Option<Arc<HeaderMap>>,
+    ) -> anyhow::Result<(HttpResponseMeta, WatchBody)> {
+        let key = watch_key(&request, impersonation.as_deref());
+        let start_resource_version = resource_version(request.uri())
+            .unwrap_or_default()
+            .to_string();
+
+        let shared = self
+            .watches
+            .lock()
+            .unwrap()
+            .get(&key)
+            .and_then(Weak::upgrade);
+        if let Some(shared) = shared {
+            if let Some(body) = shared.subscribe_from(&start_resource_version) {
+                debug!(
+                    "joined shared watch {} at {}",
+                    request.uri().path(),
+                    start_resource_version
+                );
+                return Ok((shared.meta(), body));
+            }
+        }
+
+        let (meta, body) =
+            start_request_executor(request, self.cluster_url.clone(), service, impersonation)
+                .await?;
+
+        // e.g. 410 Gone, which only applies to this request
+        if !meta.status_code.is_success() {
+            return Ok((
+                meta,
+                body.map(|chunk| chunk.map_err(anyhow::Error::from)).boxed(),
+            ));
+        }
+
+        let (shared, body) = SharedWatch::start(
+            key.clone(),
+            parse_resource_version(&start_resource_version),
+            meta,
+            body,
+            self.history_size,
+        );
+        let meta = shared.meta();
+
+        let mut watches = self.watches.lock().unwrap();
+        watches.retain(|_, watch| watch.strong_count() > 0);
+        // later requests join the newest watch, an older one keeps serving its own subscribers
+        watches.insert(key, Arc::downgrade(&shared));
+
+        Ok((meta, body))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+
+    fn request(uri: &str) -> http::Request<Vec<u8>> {
+        http::Request::get(uri).body(vec![]).unwrap()
+    }
+
+    #[test]
+    fn test_watch_key() {
+        let a = request("/api/v1/pods?watch=true&resourceVersion=10&timeoutSeconds=290");
+        let b = request("/api/v1/pods?timeoutSeconds=100&resourceVersion=42&watch=true");
+        let c = request("/api/v1/pods?watch=true&resourceVersion=10&labelSelector=app%3Dweb");
+        assert_eq!(watch_key(&a, None), watch_key(&b, None));
+        assert_ne!(watch_key(&a, None), watch_key(&c, None));
+
+        let mut impersonation = HeaderMap::new();
+        impersonation.insert("impersonate-user", "alice".parse().unwrap());
+        assert_ne!(watch_key(&a, None), watch_key(&a, Some(&impersonation)));
+    }
+
+    #[test]
+    fn test_event_resource_version() {
+        let line = br#"{"type":"MODIFIED","object":{"kind":"Pod","metadata":{"name":"a","resourceVersion":"12"}}}"#;
+        assert_eq!(event_resource_version(line), Some("12".to_string()));
+        assert_eq!(event_resource_version(b"not json"), None);
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_from() {
+        let event = |rv: u64| {
+            Bytes::from(format!(
+                r#"{{"type":"MODIFIED","object":{{"metadata":{{"resourceVersion":"{}"}}}}}}"#,
+                rv
+            ))
+        };
+        let first_event = |shared: &Arc<SharedWatch>, rv: &str| {
+            let mut body = shared.subscribe_from(rv)?;
+            body.next().now_or_never().flatten().map(Result::unwrap)
+        };
+
+        // the upstream watch stays open while its sender is alive
+        let (_sender, body) = hyper::Body::channel();
+        let meta = HttpResponseMeta {
+            status_code: http::StatusCode::OK,
+            headers: HeaderMap::new(),
+        };
+        let (shared, _body) = SharedWatch::start("pods".to_string(), Some(10), meta, body, 2);
+        shared.state.publish(event(12));
+        shared.state.publish(event(15));
+
+        assert_eq!(first_event(&shared, "10"), Some(event(12)));
+        assert_eq!(first_event(&shared, "13"), Some(event(15)));
+        assert!(shared.subscribe_from("15").is_some());
+        assert!(shared.subscribe_from("9").is_none());
+        assert!(shared.subscribe_from("16").is_none());
+        assert!(shared.subscribe_from("latest").is_none());
+
+        // the event at 12 is dropped from the history
+        shared.state.publish(event(17));
+        assert!(shared.subscribe_from("10").is_none());
+        assert_eq!(first_event(&shared, "12"), Some(event(15)));
+    }
+}
@@ -1,63 +1,104 @@
-use crate::kube_client::KubeClientService;
+use crate::config::RuntimeConfig;
+use crate::kube_client;
+use crate::metrics::{MeteredReceiver, Metrics};
 use crate::modules::ControllerModuleMetadata;
 use futures::StreamExt;
 use std::sync::atomic::AtomicU64;
 use std::sync::atomic::Ordering;
 use std::sync::Arc;
 use std::time::Instant;
-use tokio::sync::mpsc::Receiver;
 use tokio::sync::Semaphore as AsyncSemaphore;
-use tokio_stream::wrappers::ReceiverStream;
 use tracing::debug;
+use tracing::error;
 use tracing::Instrument;
 
+mod cache;
+mod clusters;
 mod environment;
 pub mod http_engine;
+pub(crate) use cache::load_module;
+pub use cache::ModuleCache;
+pub use clusters::ClusterRegistry;
 pub use environment::Environment;
 pub mod controller_ctx;
-use lazy_static::lazy_static;
-
-lazy_static! {
-    pub static ref COMPILE_WITH_UNINSTANTIATE: bool = env!("COMPILE_WITH_UNINSTANTIATE") == "TRUE";
-    pub static ref POOL_SIZE: u32 = if *COMPILE_WITH_UNINSTANTIATE {
-        // TODO: why set pool size to 10?
-        100
-    } else {
-        1000
-    };
-}
 
 pub enum Command {
     StartModule(ControllerModuleMetadata),
 }
 
 pub async fn start(
-    receiver: Receiver<Command>,
-    cluster_url: http::Uri,
-    kube_client_service: KubeClientService,
+    mut receiver: MeteredReceiver<Command>,
+    clusters: ClusterRegistry,
     cache_path: std::path::PathBuf,
     swap_path: std::path::PathBuf,
+    runtime_config: RuntimeConfig,
+    metrics: Arc<Metrics>,
 ) -> anyhow::Result<()> {
-    let environment = Environment::new()?;
+    let environment = Environment::new(&runtime_config)?;
+    let module_cache = ModuleCache::new(cache_path, &environment)?;
     let async_client_id_counter = Arc::new(AtomicU64::new(0));
-    let async_active_client_counter = Arc::new(AsyncSemaphore::new(*POOL_SIZE as usize));
+    let async_active_client_counter =
+        Arc::new(AsyncSemaphore::new(runtime_config.pool_size() as usize));
 
-    ReceiverStream::new(receiver)
+    futures::stream::poll_fn(move |cx| receiver.poll_recv(cx))
         .map(|command| async {
             match command {
                 Command::StartModule(metadata) => {
                     let async_client_id_counter_clone = async_client_id_counter.clone();
                     let async_active_client_counter_clone = async_active_client_counter.clone();
                     let environment_clone = environment.clone();
-                    let cluster_url_clone = cluster_url.clone();
-                    let kube_client_service_clone = kube_client_service.clone();
 
                     let name = metadata.name.clone();
 
+                    let cluster = match clusters.get(metadata.cluster.as_deref()) {
+                        Ok(cluster) => cluster,
+                        Err(err) => {
+                            error!("failed to create module {}: {:?}", name, err);
+                            return;
+                        }
+                    };
+                    let cluster_url_clone = cluster.kubeconfig.cluster_url.clone();
+
+                    let token_file = metadata
+                        .identity
+                        .as_ref()
+                        .and_then(|identity| identity.token_file.clone());
+                    let (kube_client_service_clone, watch_multiplexer_clone) = match token_file {
+                        None => (
+                            cluster.service.clone(),
+                            Some(cluster.watch_multiplexer.clone()),
+                        ),
+                        // a module with its own token needs its own client, the shared one adds the host credentials
+                        Some(token_file) => match clusters
+                            .client_service(
+                                &cluster.name,
+                                kube_client::with_token_file(&cluster.kubeconfig, &token_file),
+                            )
+                            .await
+                        {
+                            // its watches can't be shared, they are made with another identity
+                            Ok(service) => (service, None),
+                            Err(err) => {
+                                error!("failed to create the client of module {}: {:?}", name, err);
+                                return;
+                            }
+                        },
+                    };
+                    let kube_client_service_clone = match cluster.limits.module(
+                        kube_client_service_clone,
+                        metadata.rate_limit.as_ref(),
+                        metadata.priority,
+                    ) {
+                        Ok(service) => service,
+                        Err(err) => {
+                            error!("failed to create the client of module {}: {:?}", name, err);
+                            return;
+                        }
+                    };
+
                     let start = Instant::now();
-                    let serialized_wasm_path = environment_clone
-                        .cache_precompile(metadata.wasm.clone(), cache_path.clone())
-                        .await
+                    let serialized_wasm_path = module_cache
+                        .get_or_compile(&environment_clone, &metadata.wasm)
                         .expect("precompiling failed");
                     debug!("precompilation: {} {:?}", name, start.elapsed());
 
@@ -67,24 +108,34 @@ pub async fn start(
                         swap_path.join(format!("worker_{}_mem.bin", async_client_id));
 
                     let start = Instant::now();
-                    let mut module = environment_clone
-                        .new_controller_module(
-                            metadata,
-                            serialized_wasm_path,
-                            client_swap_path,
-                            async_client_id,
-                            async_active_client_counter_clone,
-                            cluster_url_clone,
-                            kube_client_service_clone,
-                        )
-                        .expect("failed to create module");
+                    let mut module = match environment_clone.new_controller_module(
+                        metadata,
+                        serialized_wasm_path,
+                        client_swap_path,
+                        async_client_id,
+                        async_active_client_counter_clone,
+                        cluster_url_clone,
+                        kube_client_service_clone,
+                        watch_multiplexer_clone,
+                        &metrics,
+                    ) {
+                        Ok(module) => module,
+                        Err(err) => {
+                            error!("failed to create module {}: {:?}", name, err);
+                            return;
+                        }
+                    };
 
                     debug!("compilation: {} {:?}", name, start.elapsed());
 
                     tokio::spawn(async move {
                         module
                             .start()
-                            .instrument(tracing::debug_span!("client", client_id = async_client_id))
+                            .instrument(tracing::debug_span!(
+                                "client",
+                                client_id = async_client_id,
+                                module = %name
+                            ))
                             .await
                             .expect("The module execution failed")
                     });
@@ -1,3 +1,5 @@
+use serde::{Deserialize, Serialize};
+
 #[derive(Debug)]
 pub enum SpawnerError {
     SpawnerNotInitialized,
@@ -12,3 +14,61 @@ impl std::fmt::Display for SpawnerError {
 }
 
 impl std::error::Error for SpawnerError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum HostErrorKind {
+    /// The host could not decode the request
+    InvalidRequest,
+    /// The request could not be sent or the response could not be received
+    Transport,
+    /// Any other failure on the host side
+    Internal,
+    /// The request is not allowed for this module
+    Denied,
+}
+
+/// Error reported by the host for an async request, sent as payload of `wakeup`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostError {
+    pub kind: HostErrorKind,
+    pub message: String,
+}
+
+impl std::fmt::Display for HostError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?} error on the host: {}", self.kind, self.message)
+    }
+}
+
+impl std::error::Error for HostError {}
+
+#[derive(Debug)]
+pub enum AbiError {
+    /// The request could not be serialized
+    Serialize(bincode::Error),
+    /// The response of the host could not be decoded
+    Decode(bincode::Error),
+    /// The host finished the request without sending a response
+    MissingResponse,
+    /// The host failed to execute the request
+    Host(HostError),
+}
+
+impl std::fmt::Display for AbiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AbiError::Serialize(err) => write!(f, "Failed to serialize the request: {}", err),
+            AbiError::Decode(err) => write!(f, "Failed to decode the response: {}", err),
+            AbiError::MissingResponse => write!(f, "The host did not send a response."),
+            AbiError::Host(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for AbiError {}
+
+impl From<HostError> for AbiError {
+    fn from(err: HostError) -> Self {
+        AbiError::Host(err)
+    }
+}
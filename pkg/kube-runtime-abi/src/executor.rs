@@ -1,46 +1,64 @@
 use futures::executor::{LocalPool, LocalSpawner};
 use futures::task::noop_waker;
 use futures::Stream;
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{HashMap, VecDeque};
+use std::convert::TryInto;
 use std::future::Future;
-use std::mem;
 use std::ops::Deref;
 use std::pin::Pin;
 use std::rc::Rc;
-use std::sync::{Arc, Mutex, Once};
+use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
 
-use crate::SpawnerError;
+use crate::{HostError, SpawnerError};
 
-static mut SPAWNER: Option<LocalSpawner> = None;
+// flags passed to `wakeup`
+const WAKEUP_FINISHED: u32 = 1;
+const WAKEUP_ERROR: u32 = 2;
+// only used in frames of `wakeup_buffered`, `wakeup` gets a null pointer instead
+const WAKEUP_VALUE: u32 = 4;
+
+// frame header: stream id (u64), flags (u32), payload length (u32)
+const FRAME_HEADER_SIZE: usize = 16;
+const RECEIVE_BUFFER_SIZE: usize = 64 * 1024;
+
+#[link(wasm_import_module = "async-abi")]
+extern "C" {
+    // Stops the request on the host, no more results are delivered for it
+    fn cancel(id: u64);
+    // Registers the memory in which the host writes results for `wakeup_buffered`
+    fn register_receive_buffer(ptr: *const u8, len: u32);
+}
+
+// wasm modules are single-threaded, the state is per thread so that tests can run in parallel
+thread_local! {
+    static EXECUTOR: Rc<RefCell<LocalPool>> = Rc::new(RefCell::new(LocalPool::new()));
+    static SPAWNER: RefCell<Option<LocalSpawner>> = const { RefCell::new(None) };
+    static PENDING_ASYNC: Rc<RefCell<HashMap<u64, Arc<Mutex<AsyncState>>>>> =
+        Rc::new(RefCell::new(HashMap::new()));
+    static RECEIVE_BUFFER: Cell<*mut u8> = const { Cell::new(std::ptr::null_mut()) };
+}
+
+/// Version of the host/guest ABI implemented by this crate
+pub const ABI_VERSION: u32 = 9;
+
+// The version is stored in a custom section, so the host can check it before instantiating the module.
+// It lives next to `wakeup`, which every guest exports, to make sure the linker keeps it.
+#[cfg(target_arch = "wasm32")]
+#[used]
+#[link_section = "kube-runtime-abi.version"]
+static ABI_VERSION_SECTION: [u8; 4] = ABI_VERSION.to_le_bytes();
 
 pub fn get_mut_executor() -> Rc<RefCell<LocalPool>> {
-    // Initialize it to a null value
-    static mut SINGLETON: *const Rc<RefCell<LocalPool>> = 0 as *const Rc<RefCell<LocalPool>>;
-    static ONCE: Once = Once::new();
-
-    unsafe {
-        ONCE.call_once(|| {
-            // Make it
-            let singleton = Rc::new(RefCell::new(LocalPool::new()));
-
-            // Put it in the heap so it can outlive this call
-            SINGLETON = mem::transmute::<
-                Box<Rc<RefCell<LocalPool>>>,
-                *const Rc<RefCell<LocalPool>>,
-            >(Box::new(singleton));
-        });
-
-        let pool = (*SINGLETON).clone();
-        SPAWNER = Some(pool.borrow_mut().spawner());
-
-        pool
-    }
+    let pool = EXECUTOR.with(Rc::clone);
+    SPAWNER.with(|spawner| *spawner.borrow_mut() = Some(pool.borrow_mut().spawner()));
+
+    pool
 }
 
 pub fn get_spawner() -> Result<LocalSpawner, SpawnerError> {
-    if let Some(spawner) = unsafe { SPAWNER.clone() } {
+    if let Some(spawner) = SPAWNER.with(|spawner| spawner.borrow().clone()) {
         Ok(spawner)
     } else {
         Err(SpawnerError::SpawnerNotInitialized)
@@ -48,32 +66,23 @@ pub fn get_spawner() -> Result<LocalSpawner, SpawnerError> {
 }
 
 fn get_pending_async() -> Rc<RefCell<HashMap<u64, Arc<Mutex<AsyncState>>>>> {
-    // Initialize it to a null value
-    static mut SINGLETON: *const Rc<RefCell<HashMap<u64, Arc<Mutex<AsyncState>>>>> =
-        0 as *const Rc<RefCell<HashMap<u64, Arc<Mutex<AsyncState>>>>>;
-    static ONCE: Once = Once::new();
-
-    unsafe {
-        ONCE.call_once(|| {
-            // Make it
-            let singleton: Rc<RefCell<HashMap<u64, Arc<Mutex<AsyncState>>>>> =
-                Rc::new(RefCell::new(HashMap::new()));
-
-            // Put it in the heap so it can outlive this call
-            SINGLETON = mem::transmute::<
-                Box<Rc<RefCell<HashMap<u64, Arc<Mutex<AsyncState>>>>>>,
-                *const Rc<RefCell<HashMap<u64, Arc<Mutex<AsyncState>>>>>,
-            >(Box::new(singleton));
-        });
-
-        (*SINGLETON).clone()
-    }
+    PENDING_ASYNC.with(Rc::clone)
 }
 
-pub fn start_async(future_id: u64) -> AbiAsync {
+fn init_receive_buffer() {
+    RECEIVE_BUFFER.with(|receive_buffer| {
+        if receive_buffer.get().is_null() {
+            // never freed, the host keeps writing into it for the lifetime of the module
+            let buffer = Box::leak(vec![0u8; RECEIVE_BUFFER_SIZE].into_boxed_slice());
+            receive_buffer.set(buffer.as_mut_ptr());
+            unsafe { register_receive_buffer(buffer.as_ptr(), RECEIVE_BUFFER_SIZE as u32) };
+        }
+    });
+}
+
+fn register_pending(future_id: u64) -> Arc<Mutex<AsyncState>> {
     let state = Arc::new(Mutex::new(AsyncState {
-        has_value: false,
-        value: None,
+        results: VecDeque::new(),
         waker: noop_waker(),
     }));
     get_pending_async()
@@ -81,71 +90,179 @@ pub fn start_async(future_id: u64) -> AbiAsync {
         .borrow_mut()
         .insert(future_id, state.clone());
 
+    state
+}
+
+pub fn start_async(future_id: u64) -> AbiAsync {
+    init_receive_buffer();
+
+    let state = register_pending(future_id);
+
     AbiAsync {
         shared_state: state,
+        _cancel_guard: Arc::new(CancelGuard { future_id }),
     }
 }
 
 #[no_mangle]
-pub extern "C" fn wakeup(stream_id: u64, finished: u32, ptr: *const u32, len: u32) {
-    {
-        let state_arc = get_pending_async()
-            .deref()
-            .borrow_mut()
-            .get(&stream_id)
-            .unwrap()
-            .clone();
+pub extern "C" fn wakeup(stream_id: u64, flags: u32, ptr: *const u32, len: u32) {
+    let payload = if !ptr.is_null() {
+        Some(unsafe { Vec::from_raw_parts(ptr as *mut u8, len as usize, len as usize) })
+    } else {
+        None
+    };
 
-        let mut state = state_arc.lock().unwrap();
+    deliver(stream_id, flags, payload);
 
-        if !ptr.is_null() {
-            state.value =
-                Some(unsafe { Vec::from_raw_parts(ptr as *mut u8, len as usize, len as usize) });
-        }
+    // Let's try to execute stuff up to the point where there isn't anything else to execute
+    get_mut_executor().deref().borrow_mut().run_until_stalled();
+}
+
+/// Like `wakeup`, but the host wrote one or more results as frames in the receive buffer
+#[no_mangle]
+pub extern "C" fn wakeup_buffered(len: u32) {
+    // the payloads are copied out, the host reuses the buffer for the next wakeup
+    let buffer = RECEIVE_BUFFER.with(Cell::get);
+    let frames = unsafe { std::slice::from_raw_parts(buffer, len as usize) };
+    deliver_frames(frames);
+
+    get_mut_executor().deref().borrow_mut().run_until_stalled();
+}
+
+/// Like `wakeup_buffered`, for batches that don't fit in the receive buffer
+#[no_mangle]
+pub extern "C" fn wakeup_batch(ptr: *const u32, len: u32) {
+    let frames = unsafe { Vec::from_raw_parts(ptr as *mut u8, len as usize, len as usize) };
+    deliver_frames(&frames);
+    drop(frames);
+
+    get_mut_executor().deref().borrow_mut().run_until_stalled();
+}
+
+fn deliver_frames(frames: &[u8]) {
+    let mut results = Vec::new();
 
-        state.has_value = true;
-        state.waker.wake_by_ref();
+    let mut offset = 0;
+    while offset + FRAME_HEADER_SIZE <= frames.len() {
+        let header = &frames[offset..offset + FRAME_HEADER_SIZE];
+        let stream_id = u64::from_le_bytes(header[0..8].try_into().unwrap());
+        let flags = u32::from_le_bytes(header[8..12].try_into().unwrap());
+        let payload_len = u32::from_le_bytes(header[12..16].try_into().unwrap()) as usize;
 
-        if finished == 1 {
-            get_pending_async().deref().borrow_mut().remove(&stream_id);
+        offset += FRAME_HEADER_SIZE;
+        if offset + payload_len > frames.len() {
+            // the rest of the buffer can't be trusted, the request fails instead of the module
+            let error = HostError {
+                kind: crate::HostErrorKind::Internal,
+                message: "truncated result frame".to_string(),
+            };
+            results.push((
+                stream_id,
+                WAKEUP_ERROR | WAKEUP_FINISHED,
+                bincode::serialize(&error).ok(),
+            ));
+            break;
         }
+
+        let payload = if flags & WAKEUP_VALUE != 0 {
+            Some(frames[offset..offset + payload_len].to_vec())
+        } else {
+            None
+        };
+        offset += payload_len;
+
+        results.push((stream_id, flags, payload));
     }
 
-    // Let's try to execute stuff up to the point where there isn't anything else to execute
-    get_mut_executor().deref().borrow_mut().run_until_stalled();
+    // in order, so the results of a stream are received in the order the host sent them
+    for (stream_id, flags, payload) in results {
+        deliver(stream_id, flags, payload);
+    }
+}
+
+fn deliver(stream_id: u64, flags: u32, payload: Option<Vec<u8>>) {
+    let state_arc = match get_pending_async().deref().borrow().get(&stream_id) {
+        Some(state_arc) => state_arc.clone(),
+        // results that were already queued when the request was cancelled
+        None => return,
+    };
+
+    let mut state = state_arc.lock().unwrap();
+
+    let result = if flags & WAKEUP_ERROR != 0 {
+        Err(payload
+            .and_then(|payload| bincode::deserialize(&payload).ok())
+            .unwrap_or_else(|| HostError {
+                kind: crate::HostErrorKind::Internal,
+                message: "undecodable error".to_string(),
+            }))
+    } else {
+        Ok(payload)
+    };
+
+    // a batch can hold several results of the same request, e.g. the meta and the body of a response
+    state.results.push_back(result);
+    state.waker.wake_by_ref();
+
+    if flags & WAKEUP_FINISHED != 0 {
+        get_pending_async().deref().borrow_mut().remove(&stream_id);
+    }
 }
 
 /// Shared state between the future and the waiting thread
 struct AsyncState {
-    has_value: bool, // since value's value can be None, we add this boolean
-    value: Option<Vec<u8>>,
+    /// Results in the order the host delivered them, a value of `None` ends a stream
+    results: VecDeque<Result<Option<Vec<u8>>, HostError>>,
     waker: Waker,
 }
 
 impl Future for AsyncState {
-    type Output = Option<Vec<u8>>;
+    type Output = Result<Option<Vec<u8>>, HostError>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        if self.has_value {
-            self.has_value = false;
-            Poll::Ready(self.value.take())
-        } else {
-            self.waker = cx.waker().clone();
-            Poll::Pending
+        match self.results.pop_front() {
+            Some(result) => Poll::Ready(result),
+            None => {
+                self.waker = cx.waker().clone();
+                Poll::Pending
+            }
         }
     }
 }
 
 impl Stream for AsyncState {
-    type Item = Vec<u8>;
+    type Item = Result<Vec<u8>, HostError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        if self.has_value {
-            self.has_value = false;
-            Poll::Ready(self.value.take())
-        } else {
-            self.waker = cx.waker().clone();
-            Poll::Pending
+        match self.results.pop_front() {
+            Some(Ok(value)) => Poll::Ready(value.map(Ok)),
+            Some(Err(err)) => {
+                // the request is finished, end the stream on the next poll
+                self.results.push_front(Ok(None));
+                Poll::Ready(Some(Err(err)))
+            }
+            None => {
+                self.waker = cx.waker().clone();
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// Cancels the request on the host when the last clone of its `AbiAsync` is dropped before it finished
+struct CancelGuard {
+    future_id: u64,
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        let pending = get_pending_async()
+            .deref()
+            .borrow_mut()
+            .remove(&self.future_id);
+
+        if pending.is_some() {
+            unsafe { cancel(self.future_id) };
         }
     }
 }
@@ -153,10 +270,11 @@ impl Stream for AsyncState {
 #[derive(Clone)]
 pub struct AbiAsync {
     shared_state: Arc<Mutex<AsyncState>>,
+    _cancel_guard: Arc<CancelGuard>,
 }
 
 impl Future for AbiAsync {
-    type Output = Option<Vec<u8>>;
+    type Output = Result<Option<Vec<u8>>, HostError>;
 
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let state = &mut *self.shared_state.lock().unwrap();
@@ -165,10 +283,85 @@ impl Future for AbiAsync {
 }
 
 impl Stream for AbiAsync {
-    type Item = Vec<u8>;
+    type Item = Result<Vec<u8>, HostError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let state = &mut *self.shared_state.lock().unwrap();
         unsafe { Pin::new_unchecked(state) }.poll_next(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(frames: &mut Vec<u8>, stream_id: u64, flags: u32, payload: &[u8]) {
+        frames.extend_from_slice(&stream_id.to_le_bytes());
+        frames.extend_from_slice(&flags.to_le_bytes());
+        frames.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        frames.extend_from_slice(payload);
+    }
+
+    #[test]
+    fn test_truncated_frame() {
+        let state = register_pending(1);
+
+        let mut frames = Vec::new();
+        frame(&mut frames, 1, WAKEUP_VALUE, b"truncated");
+        frames.truncate(frames.len() - 2);
+        deliver_frames(&frames);
+
+        let result = futures::executor::block_on(&mut *state.lock().unwrap());
+        assert_eq!(result.unwrap_err().kind, crate::HostErrorKind::Internal);
+        assert!(!get_pending_async().borrow().contains_key(&1));
+    }
+
+    #[test]
+    fn test_results_of_one_request_in_one_batch() {
+        let response = register_pending(2);
+        let stream = register_pending(3);
+
+        // the meta and the body of a response, and a burst of watch events, in one wakeup
+        let mut frames = Vec::new();
+        frame(&mut frames, 2, WAKEUP_VALUE, b"meta");
+        frame(&mut frames, 3, WAKEUP_VALUE, b"event 1");
+        frame(&mut frames, 2, WAKEUP_VALUE | WAKEUP_FINISHED, b"body");
+        frame(&mut frames, 3, WAKEUP_VALUE, b"event 2");
+        frame(&mut frames, 3, WAKEUP_FINISHED, &[]);
+        deliver_frames(&frames);
+
+        let mut response = response.lock().unwrap();
+        let meta = futures::executor::block_on(&mut *response).unwrap();
+        let body = futures::executor::block_on(&mut *response).unwrap();
+        assert_eq!(meta.as_deref(), Some(&b"meta"[..]));
+        assert_eq!(body.as_deref(), Some(&b"body"[..]));
+
+        let mut stream = stream.lock().unwrap();
+        let events = futures::executor::block_on_stream(&mut *stream)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(events, vec![b"event 1".to_vec(), b"event 2".to_vec()]);
+    }
+
+    #[test]
+    fn test_forbidden_stream_in_one_batch() {
+        let state = register_pending(4);
+
+        // a stream the host denied is answered at once with its meta, the `Status` body and its end
+        let mut frames = Vec::new();
+        frame(&mut frames, 4, WAKEUP_VALUE, b"403 meta");
+        frame(&mut frames, 4, WAKEUP_VALUE, b"status");
+        frame(&mut frames, 4, WAKEUP_FINISHED, &[]);
+        deliver_frames(&frames);
+
+        let mut state = state.lock().unwrap();
+        let meta = futures::executor::block_on(&mut *state).unwrap();
+        assert_eq!(meta.as_deref(), Some(&b"403 meta"[..]));
+
+        let body = futures::executor::block_on_stream(&mut *state)
+            .map(Result::unwrap)
+            .collect::<Vec<_>>();
+        assert_eq!(body, vec![b"status".to_vec()]);
+        assert!(!get_pending_async().borrow().contains_key(&4));
+    }
+}
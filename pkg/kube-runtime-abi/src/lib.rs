@@ -4,13 +4,25 @@ mod delay;
 mod error;
 mod executor;
 pub(crate) mod http_data;
+mod log;
 mod memory;
 mod requestor;
+mod watch_filter;
 
 pub use delay::register_delay;
+pub use error::AbiError;
+pub use error::HostError;
+pub use error::HostErrorKind;
 pub use error::SpawnerError;
 pub use executor::get_mut_executor;
 pub use executor::get_spawner;
 pub use executor::start_async;
+pub use executor::ABI_VERSION;
+pub use log::init_logging;
+pub use log::AbiLogLayer;
+pub use requestor::execute_outbound_request;
 pub use requestor::execute_request;
 pub use requestor::execute_request_stream;
+pub use requestor::execute_request_stream_filtered;
+pub use requestor::BodyStream;
+pub use watch_filter::WatchFilter;
@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use tracing::field::{Field, Visit};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context, Layer, SubscriberExt};
+use tracing_subscriber::util::SubscriberInitExt;
+
+#[link(wasm_import_module = "log-abi")]
+extern "C" {
+    fn event(ptr: *const u8, len: usize);
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum LogLevel {
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// Data structure to serialize/deserialize a log event
+#[derive(Serialize, Deserialize)]
+pub(crate) struct LogRecord {
+    pub(crate) level: LogLevel,
+    pub(crate) target: String,
+    pub(crate) message: String,
+    pub(crate) fields: Vec<(String, String)>,
+}
+
+#[derive(Default)]
+struct RecordVisitor {
+    message: String,
+    fields: Vec<(String, String)>,
+}
+
+impl Visit for RecordVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.fields
+                .push((field.name().to_string(), value.to_string()));
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "message" {
+            self.message = format!("{:?}", value);
+        } else {
+            self.fields
+                .push((field.name().to_string(), format!("{:?}", value)));
+        }
+    }
+}
+
+/// Layer that forwards the tracing events of the guest to the host, which emits them
+/// in its own tracing pipeline under the span of the module.
+pub struct AbiLogLayer;
+
+impl<S: Subscriber> Layer<S> for AbiLogLayer {
+    fn on_event(&self, ev: &Event<'_>, _ctx: Context<'_, S>) {
+        let metadata = ev.metadata();
+
+        let mut visitor = RecordVisitor::default();
+        ev.record(&mut visitor);
+
+        let level = match *metadata.level() {
+            tracing::Level::TRACE => LogLevel::Trace,
+            tracing::Level::DEBUG => LogLevel::Debug,
+            tracing::Level::INFO => LogLevel::Info,
+            tracing::Level::WARN => LogLevel::Warn,
+            tracing::Level::ERROR => LogLevel::Error,
+        };
+
+        let record = LogRecord {
+            level,
+            target: metadata.target().to_string(),
+            message: visitor.message,
+            fields: visitor.fields,
+        };
+
+        if let Ok(bytes) = bincode::serialize(&record) {
+            unsafe { event(bytes.as_ptr(), bytes.len()) };
+        }
+    }
+}
+
+/// Install a global subscriber that forwards all events to the host.
+/// The level is read from `RUST_LOG` (e.g. "info") and defaults to info.
+pub fn init_logging() {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse::<LevelFilter>().ok())
+        .unwrap_or(LevelFilter::INFO);
+
+    tracing_subscriber::registry()
+        .with(level)
+        .with(AbiLogLayer)
+        .init();
+}
@@ -1,43 +1,155 @@
 use super::http_data::HttpRequest;
 use super::http_data::HttpResponseMeta;
 use super::start_async;
+use crate::executor::AbiAsync;
+use crate::AbiError;
+use crate::WatchFilter;
+use bytes::Bytes;
 use futures::Stream;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
 #[link(wasm_import_module = "http-proxy-abi")]
 extern "C" {
     fn request(ptr: *const u8, len: usize, stream: u32) -> u64;
+    fn request_stream(ptr: *const u8, len: usize) -> u64;
+    fn request_stream_filtered(
+        ptr: *const u8,
+        len: usize,
+        filter_ptr: *const u8,
+        filter_len: usize,
+    ) -> u64;
+}
+
+#[link(wasm_import_module = "http-outbound-abi")]
+extern "C" {
+    fn outbound_request(ptr: *const u8, len: usize) -> u64;
+}
+
+/// Body of a streamed response.
+///
+/// Yields the chunks as they are received by the host and ends after the last chunk,
+/// or after an error if the connection failed while streaming.
+pub struct BodyStream {
+    chunks: AbiAsync,
+    done: bool,
+}
+
+impl Stream for BodyStream {
+    type Item = Result<Bytes, AbiError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        if self.done {
+            return Poll::Ready(None);
+        }
+
+        match Pin::new(&mut self.chunks).poll_next(cx) {
+            Poll::Ready(Some(Ok(chunk))) => Poll::Ready(Some(Ok(Bytes::from(chunk)))),
+            Poll::Ready(Some(Err(err))) => {
+                self.done = true;
+                Poll::Ready(Some(Err(err.into())))
+            }
+            Poll::Ready(None) => {
+                self.done = true;
+                Poll::Ready(None)
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
 }
 
 pub async fn execute_request_stream(
     req: http::Request<Vec<u8>>,
-) -> http::Response<impl Stream<Item = Vec<u8>>> {
+) -> Result<http::Response<BodyStream>, AbiError> {
+    let inner_request: HttpRequest<Vec<u8>> = req.into();
+    let bytes = bincode::serialize(&inner_request).map_err(AbiError::Serialize)?;
+
+    let async_request_id: u64 = unsafe { request_stream(bytes.as_ptr(), bytes.len()) };
+
+    stream_response(async_request_id).await
+}
+
+/// Like `execute_request_stream`, but the host only delivers the watch events that pass the filter
+pub async fn execute_request_stream_filtered(
+    req: http::Request<Vec<u8>>,
+    filter: &WatchFilter,
+) -> Result<http::Response<BodyStream>, AbiError> {
     let inner_request: HttpRequest<Vec<u8>> = req.into();
-    let bytes = bincode::serialize(&inner_request).unwrap();
+    let bytes = bincode::serialize(&inner_request).map_err(AbiError::Serialize)?;
+    let filter_bytes = bincode::serialize(filter).map_err(AbiError::Serialize)?;
 
-    let async_request_id: u64 = unsafe { request(bytes.as_ptr(), bytes.len(), 1) };
+    let async_request_id: u64 = unsafe {
+        request_stream_filtered(
+            bytes.as_ptr(),
+            bytes.len(),
+            filter_bytes.as_ptr(),
+            filter_bytes.len(),
+        )
+    };
 
+    stream_response(async_request_id).await
+}
+
+async fn stream_response(async_request_id: u64) -> Result<http::Response<BodyStream>, AbiError> {
     let abi_async = start_async(async_request_id);
 
-    let response_raw = abi_async.clone().await.unwrap(); // get first value from future trait
+    let response_raw = abi_async
+        .clone()
+        .await? // get first value from future trait
+        .ok_or(AbiError::MissingResponse)?;
 
-    let response: HttpResponseMeta = bincode::deserialize(&response_raw).unwrap();
+    let response: HttpResponseMeta =
+        bincode::deserialize(&response_raw).map_err(AbiError::Decode)?;
 
-    response.into(abi_async) // get next values from stream trait
+    Ok(response.into(BodyStream {
+        chunks: abi_async, // get next values from stream trait
+        done: false,
+    }))
 }
 
-pub async fn execute_request(req: http::Request<Vec<u8>>) -> http::Response<Vec<u8>> {
+pub async fn execute_request(
+    req: http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>, AbiError> {
     let inner_request: HttpRequest<Vec<u8>> = req.into();
-    let bytes = bincode::serialize(&inner_request).unwrap();
+    let bytes = bincode::serialize(&inner_request).map_err(AbiError::Serialize)?;
 
     let async_request_id: u64 = unsafe { request(bytes.as_ptr(), bytes.len(), 0) };
 
     let abi_async = start_async(async_request_id);
 
-    let response_raw = abi_async.clone().await.unwrap(); // get first value from future trait
+    let response_raw = abi_async
+        .clone()
+        .await? // get first value from future trait
+        .ok_or(AbiError::MissingResponse)?;
+
+    let response: HttpResponseMeta =
+        bincode::deserialize(&response_raw).map_err(AbiError::Decode)?;
+
+    let body = abi_async.await?.unwrap_or_default();
+
+    Ok(response.into(body)) // get next values from stream trait
+}
+
+/// Execute a request to a service outside of the cluster.
+///
+/// The uri must be absolute and its origin must be allowed by the `outboundHttp` config of the module,
+/// otherwise the request fails with a `Denied` host error.
+pub async fn execute_outbound_request(
+    req: http::Request<Vec<u8>>,
+) -> Result<http::Response<Vec<u8>>, AbiError> {
+    let inner_request: HttpRequest<Vec<u8>> = req.into();
+    let bytes = bincode::serialize(&inner_request).map_err(AbiError::Serialize)?;
+
+    let async_request_id: u64 = unsafe { outbound_request(bytes.as_ptr(), bytes.len()) };
+
+    let abi_async = start_async(async_request_id);
+
+    let response_raw = abi_async.clone().await?.ok_or(AbiError::MissingResponse)?;
 
-    let response: HttpResponseMeta = bincode::deserialize(&response_raw).unwrap();
+    let response: HttpResponseMeta =
+        bincode::deserialize(&response_raw).map_err(AbiError::Decode)?;
 
-    let body = abi_async.await.unwrap();
+    let body = abi_async.await?.unwrap_or_default();
 
-    response.into(body) // get next values from stream trait
+    Ok(response.into(body))
 }
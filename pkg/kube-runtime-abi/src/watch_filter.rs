@@ -0,0 +1,36 @@
+use serde::Serialize;
+
+/// Filter the host applies to the events of a watch before they are delivered to the module,
+/// so the module is not woken up for events it is not interested in.
+///
+/// `BOOKMARK` and `ERROR` events are always delivered.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct WatchFilter {
+    labels: Vec<(String, String)>,
+    fields: Vec<(String, String)>,
+    generation_changed: bool,
+}
+
+impl WatchFilter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only deliver events of objects with this label value
+    pub fn label(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.labels.push((key.into(), value.into()));
+        self
+    }
+
+    /// Only deliver events of objects with this value at a dot separated path (e.g. `status.phase`)
+    pub fn field(mut self, path: impl Into<String>, value: impl Into<String>) -> Self {
+        self.fields.push((path.into(), value.into()));
+        self
+    }
+
+    /// Only deliver `MODIFIED` events if `metadata.generation` changed, e.g. to skip status updates
+    pub fn generation_changed(mut self) -> Self {
+        self.generation_changed = true;
+        self
+    }
+}